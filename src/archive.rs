@@ -0,0 +1,189 @@
+//! Moving files into and out of containers through Docker's archive (tar) API.
+//!
+//! Going through the API instead of running `cp` inside the container works for any
+//! image, including those without a shell, and works on stopped containers.
+
+use bollard::Docker;
+use bollard::container::{DownloadFromContainerOptions, UploadToContainerOptions};
+use futures_util::stream::StreamExt;
+use std::path::Path;
+
+/// Pack a host directory into a tar archive whose entries live under `target`.
+///
+/// `target` is an absolute container path; the archive is meant to be extracted at `/`,
+/// which lets Docker create `target` even if the image does not have it. The run records
+/// in the directory's `.forge` are left out.
+pub fn pack_directory(source: &Path, target: &str) -> std::io::Result<Vec<u8>> {
+    let target = Path::new(target.trim_start_matches('/'));
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    builder.append_dir(target, source)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        if entry.file_name() == crate::runs::FORGE_DIR {
+            continue;
+        }
+        let name = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            builder.append_dir_all(name, entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), name)?;
+        }
+    }
+    builder.into_inner()
+}
+
+/// Extract a tar archive produced by [`pack_directory`] into a container.
+///
+/// The container only needs to exist; it does not have to be running.
+pub async fn upload(
+    docker: &Docker,
+    container_id: &str,
+    archive: Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let options = UploadToContainerOptions {
+        path: "/".to_string(),
+        ..Default::default()
+    };
+    docker
+        .upload_to_container(container_id, Some(options), archive.into())
+        .await?;
+    Ok(())
+}
+
+/// Download a container path as a tar archive.
+///
+/// Docker names the archive entries after the last component of `path`, e.g.
+/// `node_modules/...` for `/app/node_modules`. Returns `None` if the path does not exist.
+pub async fn download(
+    docker: &Docker,
+    container_id: &str,
+    path: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+    let options = DownloadFromContainerOptions {
+        path: path.to_string(),
+    };
+    let mut stream = docker.download_from_container(container_id, Some(options));
+
+    let mut archive = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(bytes) => archive.extend_from_slice(&bytes),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(archive))
+}
+
+/// Move the entries of an archive from [`download`] under the parent directory of `path`,
+/// so that it can be uploaded at `/` to recreate `path`.
+pub fn reroot(archive: &[u8], path: &str) -> std::io::Result<Vec<u8>> {
+    let parent = Path::new(path.trim_matches('/'))
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+
+    let mut source = tar::Archive::new(archive);
+    let mut builder = tar::Builder::new(Vec::new());
+    for entry in source.entries()? {
+        let mut entry = entry?;
+        let target = parent.join(entry.path()?);
+        let mut header = entry.header().clone();
+
+        if header.entry_type() == tar::EntryType::Link {
+            // Hard links point at another entry of the archive, which moved as well
+            let link = entry
+                .link_name()?
+                .map(|l| parent.join(l))
+                .unwrap_or_default();
+            builder.append_link(&mut header, &target, &link)?;
+        } else if header.entry_type() == tar::EntryType::Symlink {
+            let link = entry
+                .link_name()?
+                .map(|l| l.into_owned())
+                .unwrap_or_default();
+            builder.append_link(&mut header, &target, &link)?;
+        } else {
+            builder.append_data(&mut header, &target, &mut entry)?;
+        }
+    }
+    builder.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_paths(archive: &[u8]) -> Vec<String> {
+        let mut paths: Vec<String> = tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let path = e.unwrap().path().unwrap().display().to_string();
+                path.trim_end_matches('/').to_string()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn reroot_moves_entries_under_the_parent_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".yarn-integrity"), "ok").unwrap();
+        let downloaded = pack_directory(dir.path(), "node_modules").unwrap();
+
+        let rerooted = reroot(&downloaded, "/app/web/node_modules").unwrap();
+        assert_eq!(
+            entry_paths(&rerooted),
+            vec![
+                "app/web/node_modules",
+                "app/web/node_modules/.yarn-integrity"
+            ]
+        );
+
+        let mut archive = tar::Archive::new(rerooted.as_slice());
+        let mut file = archive
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|e| e.header().entry_type().is_file())
+            .unwrap();
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut file, &mut content).unwrap();
+        assert_eq!(content, "ok");
+    }
+
+    #[test]
+    fn packs_directory_under_the_target_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("main.go"), "package main").unwrap();
+        std::fs::create_dir(dir.path().join(".config")).unwrap();
+        std::fs::write(dir.path().join(".config/settings"), "x").unwrap();
+
+        let archive = pack_directory(dir.path(), "/app").unwrap();
+        assert_eq!(
+            entry_paths(&archive),
+            vec!["app", "app/.config", "app/.config/settings", "app/main.go"]
+        );
+    }
+
+    #[test]
+    fn leaves_out_run_records() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("main.go"), "package main").unwrap();
+        let run = crate::runs::run_dir(dir.path(), "20240501-142233-3f9a1c");
+        std::fs::create_dir_all(&run).unwrap();
+        std::fs::write(run.join("run.json"), "{}").unwrap();
+        std::fs::create_dir_all(dir.path().join("src/.forge")).unwrap();
+
+        let archive = pack_directory(dir.path(), "/app").unwrap();
+        assert_eq!(
+            entry_paths(&archive),
+            vec!["app", "app/main.go", "app/src", "app/src/.forge"]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Artifacts: files a step leaves behind for later steps and for inspection after the run.
+//!
+//! After a step's container exits, the paths listed under its `artifacts` are copied out
+//! through the Docker archive API into `.forge/runs/<run-id>/artifacts/<name>/`, keeping
+//! their container path: `/app/dist/main.js` is stored as `app/dist/main.js`. Steps listing
+//! the artifact in `needs_artifacts` get the files copied back to the same paths before
+//! they start.
+
+use crate::{archive, glob};
+use bollard::Docker;
+use std::path::{Path, PathBuf};
+
+/// Directory holding an artifact inside the run's artifacts directory.
+pub fn artifact_dir(artifacts_dir: &Path, name: &str) -> PathBuf {
+    artifacts_dir.join(crate::cache::sanitize_key(name))
+}
+
+/// Absolute container path of an artifact path; relative paths start at `working_dir`.
+pub fn container_path(path: &str, working_dir: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!(
+            "{}/{}",
+            working_dir.trim_end_matches('/'),
+            path.trim_start_matches("./")
+        )
+    }
+}
+
+/// Directory to download for a path: everything before its first segment with a wildcard.
+fn download_root(path: &str) -> String {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .take_while(|s| !glob::is_pattern(s))
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// Copy the files matching `paths` (absolute container paths or glob patterns) out of a
+/// finished container into `dest`. Returns the number of files copied; paths that match
+/// nothing are skipped.
+pub async fn collect(
+    docker: &Docker,
+    container_id: &str,
+    paths: &[String],
+    dest: &Path,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut count = 0;
+    for path in paths {
+        let root = download_root(path);
+        let Some(tar) = archive::download(docker, container_id, &root).await? else {
+            continue;
+        };
+        count += extract(&tar, &root, path, dest)?;
+    }
+    Ok(count)
+}
+
+/// Extract the files of a downloaded archive of `root` that match `pattern` into `dest`.
+/// A pattern without wildcards matches the path itself and everything below it.
+fn extract(tar: &[u8], root: &str, pattern: &str, dest: &Path) -> std::io::Result<usize> {
+    let pattern = if glob::is_pattern(pattern) {
+        pattern.to_string()
+    } else {
+        format!("{}/**", pattern.trim_end_matches('/'))
+    };
+    let rerooted = archive::reroot(tar, root)?;
+    let mut archive = tar::Archive::new(rerooted.as_slice());
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if glob::matches(&pattern, &path) {
+            entry.unpack_in(dest)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Pack the files of an artifact into an archive to upload at `/`, which puts them back at
+/// their container paths. Directories are left out, so that the existing directories of
+/// the container keep their owner and permissions.
+pub fn pack(dir: &Path) -> std::io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    for file in glob::find_files(dir, "**")? {
+        let relative = file.strip_prefix(dir).map_err(std::io::Error::other)?;
+        builder.append_path_with_name(&file, relative)?;
+    }
+    builder.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_container_paths() {
+        assert_eq!(container_path("dist", "/app"), "/app/dist");
+        assert_eq!(container_path("./dist/", "/app/"), "/app/dist/");
+        assert_eq!(container_path("/out/report.xml", "/app"), "/out/report.xml");
+        assert_eq!(container_path("dist", ""), "/dist");
+        assert_eq!(download_root("/app/coverage/**/*.xml"), "/app/coverage");
+        assert_eq!(download_root("/app/dist/"), "/app/dist");
+        assert_eq!(download_root("/*.log"), "/");
+    }
+
+    #[test]
+    fn collects_matching_files_and_packs_them_back() {
+        let container = tempfile::tempdir().unwrap();
+        std::fs::create_dir(container.path().join("unit")).unwrap();
+        std::fs::write(container.path().join("unit/junit.xml"), "<xml/>").unwrap();
+        std::fs::write(container.path().join("summary.txt"), "ok").unwrap();
+        // Docker names the entries of a downloaded directory after its last component
+        let downloaded = archive::pack_directory(container.path(), "coverage").unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let count = extract(
+            &downloaded,
+            "/app/coverage",
+            "/app/coverage/**/*.xml",
+            dest.path(),
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert!(dest.path().join("app/coverage/unit/junit.xml").is_file());
+        assert!(!dest.path().join("app/coverage/summary.txt").exists());
+
+        let count = extract(&downloaded, "/app/coverage", "/app/coverage", dest.path()).unwrap();
+        assert_eq!(count, 2);
+
+        let packed = pack(dest.path()).unwrap();
+        let mut paths: Vec<String> = tar::Archive::new(packed.as_slice())
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["app/coverage/summary.txt", "app/coverage/unit/junit.xml"]
+        );
+    }
+}
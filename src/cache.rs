@@ -0,0 +1,843 @@
+//! Persistent cache shared between pipeline runs.
+//!
+//! Cached directories live under `$XDG_CACHE_HOME/forge/<project>` (falling back to
+//! `~/.cache/forge`), so they survive the per-run temporary directory. `FORGE_CACHE_DIR`
+//! overrides the root, e.g. to keep caches on a bigger disk.
+//!
+//! Inside a project's cache every entry has its own directory named after its key. Keys
+//! are templates that can hash files, e.g. `node-${{ hashFiles('package-lock.json') }}`,
+//! so changing a lockfile produces a new entry instead of reusing stale dependencies.
+//! Each cached directory is stored as a tar archive (`<entry>/app/node_modules.tar`) and
+//! moved in and out of step containers through the Docker archive API.
+//!
+//! Restoring or saving an entry touches its `.last-used` marker; `forge cache prune`
+//! evicts by that time and by total size. Saving also records the time in `.saved-at`,
+//! which picks the newest entry for `restore_keys`.
+
+use crate::{CacheConfig, archive, glob};
+use bollard::Docker;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Key of the entry holding the plain `directories` list.
+const DEFAULT_KEY: &str = "default";
+
+/// Marker file whose modification time is the last time an entry was used.
+const LAST_USED: &str = ".last-used";
+
+/// File holding the time an entry was last saved, in RFC 3339 format. Unlike modification
+/// times, it survives copying the cache.
+const SAVED_AT: &str = ".saved-at";
+
+/// A cache entry with its key resolved for the current run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedEntry {
+    /// Directory of the entry inside the project's cache
+    pub key: String,
+    /// Container directories stored in the entry
+    pub paths: Vec<String>,
+    /// Entry to restore from: the exact key if it exists, else the newest `restore_keys` match
+    pub restore_from: Option<String>,
+    /// Whether steps write their directories back into the entry
+    pub save: bool,
+}
+
+impl ResolvedEntry {
+    /// Entry to restore from right now.
+    ///
+    /// Once a step of this run has saved the entry, later steps pick up its contents.
+    fn source(&self, cache_dir: &Path) -> Option<&str> {
+        if self.save && cache_dir.join(&self.key).is_dir() {
+            Some(&self.key)
+        } else {
+            self.restore_from.as_deref()
+        }
+    }
+}
+
+/// Archive file holding a container directory inside an entry.
+fn archive_path(cache_dir: &Path, key: &str, path: &str) -> PathBuf {
+    cache_dir
+        .join(key)
+        .join(format!("{}.tar", path.trim_matches('/')))
+}
+
+/// Copy cached directories into a created, not yet started, container.
+///
+/// Problems are reported as warnings: a broken cache slows a step down but must not
+/// fail it.
+pub async fn restore(
+    docker: &Docker,
+    container_id: &str,
+    cache_dir: &Path,
+    entries: &[ResolvedEntry],
+    verbose: bool,
+) {
+    for entry in entries {
+        let Some(source) = entry.source(cache_dir) else {
+            if verbose {
+                println!("  Cache '{}': miss", entry.key);
+            }
+            continue;
+        };
+        touch(&cache_dir.join(source));
+        for path in &entry.paths {
+            let file = archive_path(cache_dir, source, path);
+            let tar = match std::fs::read(&file) {
+                Ok(tar) => tar,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn(&format!("Failed to read cache '{}': {e}", file.display()));
+                    continue;
+                }
+            };
+            match archive::upload(docker, container_id, tar).await {
+                Ok(()) if verbose => println!("  Restored {path} from cache '{source}'"),
+                Ok(()) => {}
+                Err(e) => warn(&format!(
+                    "Failed to restore {path} from cache '{source}': {e}"
+                )),
+            }
+        }
+    }
+}
+
+/// Store the cached directories of a finished container.
+pub async fn save(
+    docker: &Docker,
+    container_id: &str,
+    cache_dir: &Path,
+    entries: &[ResolvedEntry],
+    verbose: bool,
+) {
+    for entry in entries.iter().filter(|e| e.save) {
+        let mut saved = false;
+        for path in &entry.paths {
+            let tar = match archive::download(docker, container_id, path).await {
+                Ok(Some(tar)) => tar,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn(&format!(
+                        "Failed to read {path} for cache '{}': {e}",
+                        entry.key
+                    ));
+                    continue;
+                }
+            };
+            let file = archive_path(cache_dir, &entry.key, path);
+            match store(&file, &tar, path) {
+                Ok(()) => {
+                    saved = true;
+                    if verbose {
+                        println!("  Saved {path} to cache '{}'", entry.key);
+                    }
+                }
+                Err(e) => warn(&format!(
+                    "Failed to save {path} to cache '{}': {e}",
+                    entry.key
+                )),
+            }
+        }
+        if saved {
+            mark_saved(&cache_dir.join(&entry.key));
+        }
+        touch(&cache_dir.join(&entry.key));
+    }
+}
+
+/// Record that an entry was used. Entries that do not exist (yet) are left alone.
+fn touch(entry_dir: &Path) {
+    if !entry_dir.is_dir() {
+        return;
+    }
+    let marker = entry_dir.join(LAST_USED);
+    let result = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&marker)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = result {
+        warn(&format!("Failed to update '{}': {e}", marker.display()));
+    }
+}
+
+/// Record that an entry was just saved.
+fn mark_saved(entry_dir: &Path) {
+    let file = entry_dir.join(SAVED_AT);
+    if let Err(e) = std::fs::write(&file, chrono::Utc::now().to_rfc3339()) {
+        warn(&format!("Failed to update '{}': {e}", file.display()));
+    }
+}
+
+/// When an entry was last saved. Entries saved before `.saved-at` existed fall back to the
+/// directory's modification time.
+fn saved_at(entry: &std::fs::DirEntry) -> Option<chrono::DateTime<chrono::Utc>> {
+    let recorded = std::fs::read_to_string(entry.path().join(SAVED_AT))
+        .ok()
+        .and_then(|text| chrono::DateTime::parse_from_rfc3339(text.trim()).ok());
+    match recorded {
+        Some(time) => Some(time.to_utc()),
+        None => entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .map(Into::into),
+    }
+}
+
+/// Write an archive under its final name only once complete, so that steps saving the
+/// same entry concurrently never leave a truncated file behind.
+fn store(file: &Path, tar: &[u8], path: &str) -> std::io::Result<()> {
+    let rerooted = archive::reroot(tar, path)?;
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = file.with_extension(format!("tar.{}.partial", uuid::Uuid::new_v4()));
+    std::fs::write(&partial, rerooted)?;
+    std::fs::rename(&partial, file)
+}
+
+fn warn(message: &str) {
+    use colored::Colorize;
+    eprintln!("{}", format!("Warning: {message}").yellow());
+}
+
+/// Resolve the keys of all configured cache entries against the existing cache.
+///
+/// Entries with an explicit key behave like GitHub Actions caches: an exact hit is
+/// restored but never overwritten, a miss falls back to the newest entry matching one of
+/// the `restore_keys` prefixes and is saved under the new key. The plain `directories`
+/// list is stored under a fixed key and updated after every step.
+pub fn resolve_entries(
+    config: &CacheConfig,
+    project_dir: &Path,
+    cache_dir: &Path,
+) -> Result<Vec<ResolvedEntry>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut resolved = Vec::new();
+
+    if !config.directories.is_empty() {
+        resolved.push(ResolvedEntry {
+            key: DEFAULT_KEY.to_string(),
+            paths: config.directories.clone(),
+            restore_from: cache_dir
+                .join(DEFAULT_KEY)
+                .is_dir()
+                .then(|| DEFAULT_KEY.to_string()),
+            save: true,
+        });
+    }
+
+    for entry in &config.entries {
+        let key = sanitize_key(&render_key(&entry.key, project_dir)?);
+        if entry_dir(cache_dir, &key).is_none() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Cache key '{}' gives '{key}', which cannot name a cache entry\n\
+                     Hint: Use letters or digits in the key, e.g. 'deps-${{{{ hashFiles('Cargo.lock') }}}}'",
+                    entry.key
+                ),
+            )));
+        }
+        if cache_dir.join(&key).is_dir() {
+            resolved.push(ResolvedEntry {
+                key: key.clone(),
+                paths: entry.paths.clone(),
+                restore_from: Some(key),
+                save: false,
+            });
+            continue;
+        }
+
+        let mut restore_from = None;
+        for prefix in &entry.restore_keys {
+            let prefix = sanitize_key(&render_key(prefix, project_dir)?);
+            restore_from = newest_with_prefix(cache_dir, &prefix);
+            if restore_from.is_some() {
+                break;
+            }
+        }
+        resolved.push(ResolvedEntry {
+            key,
+            paths: entry.paths.clone(),
+            restore_from,
+            save: true,
+        });
+    }
+
+    Ok(resolved)
+}
+
+/// Most recently saved entry whose key starts with `prefix`.
+fn newest_with_prefix(cache_dir: &Path, prefix: &str) -> Option<String> {
+    std::fs::read_dir(cache_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let saved = saved_at(&e)?;
+            name.starts_with(prefix).then_some((saved, name))
+        })
+        .max()
+        .map(|(_, name)| name)
+}
+
+/// Expand the `${{ ... }}` expressions of a key template.
+///
+/// The only supported expression is `hashFiles('pattern', ...)`, which hashes the
+/// project files matching the patterns; it yields an empty string when nothing matches.
+fn render_key(
+    template: &str,
+    project_dir: &Path,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${{") {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            return Err(invalid_key(template, "unterminated '${{'"));
+        };
+        let expression = rest[start + 3..start + end].trim();
+        rest = &rest[start + end + 2..];
+
+        let patterns = expression
+            .strip_prefix("hashFiles(")
+            .and_then(|args| args.strip_suffix(')'))
+            .and_then(parse_string_args)
+            .ok_or_else(|| {
+                invalid_key(template, &format!("unsupported expression '{expression}'"))
+            })?;
+        rendered.push_str(&hash_files(project_dir, &patterns)?);
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+fn invalid_key(template: &str, reason: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Invalid cache key '{template}': {reason}\n\
+             Hint: Cache keys support ${{{{ hashFiles('path/or/glob', ...) }}}} expressions"
+        ),
+    ))
+}
+
+/// Parse a comma separated list of quoted strings.
+fn parse_string_args(args: &str) -> Option<Vec<String>> {
+    args.split(',')
+        .map(|arg| {
+            let arg = arg.trim();
+            ['\'', '"'].iter().find_map(|&quote| {
+                arg.strip_prefix(quote)
+                    .and_then(|a| a.strip_suffix(quote))
+                    .map(str::to_string)
+            })
+        })
+        .collect()
+}
+
+/// SHA-256 over the project files matching `patterns`, or `""` if none match.
+fn hash_files(
+    project_dir: &Path,
+    patterns: &[String],
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        if glob::is_pattern(pattern) {
+            files.extend(glob::find_files(project_dir, pattern)?);
+        } else {
+            let file = project_dir.join(pattern);
+            if file.is_file() {
+                files.push(file);
+            }
+        }
+    }
+    files.sort();
+    files.dedup();
+
+    if files.is_empty() {
+        return Ok(String::new());
+    }
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(Sha256::digest(std::fs::read(&file)?));
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Make a key usable as a directory name. Keeps prefixes of keys prefixes of each other.
+pub fn sanitize_key(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Directory of the entry `key` inside `cache_dir`, or `None` for keys such as `..` that
+/// would name the cache directory itself or one outside of it.
+pub fn entry_dir(cache_dir: &Path, key: &str) -> Option<PathBuf> {
+    // Sanitized keys have no separators, so only these escape the cache directory
+    let key = sanitize_key(key);
+    (!matches!(key.as_str(), "" | "." | "..")).then(|| cache_dir.join(key))
+}
+
+/// Root directory holding the caches of all projects.
+pub fn cache_root() -> PathBuf {
+    cache_root_from(|var| std::env::var_os(var).map(PathBuf::from))
+}
+
+fn cache_root_from(var: impl Fn(&str) -> Option<PathBuf>) -> PathBuf {
+    let non_empty = |name: &str| var(name).filter(|p| !p.as_os_str().is_empty());
+
+    if let Some(dir) = non_empty("FORGE_CACHE_DIR") {
+        return dir;
+    }
+    if let Some(dir) = non_empty("XDG_CACHE_HOME") {
+        return dir.join("forge");
+    }
+    if let Some(home) = non_empty("HOME").or_else(|| non_empty("USERPROFILE")) {
+        return home.join(".cache").join("forge");
+    }
+    std::env::temp_dir().join("forge-cache")
+}
+
+/// Name of a project's cache directory.
+///
+/// The directory name keeps the cache recognisable on disk, the hash of the full path
+/// keeps two checkouts with the same name apart.
+pub fn project_key(project_dir: &Path) -> String {
+    let name: String = project_dir
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "project".to_string())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let digest = Sha256::digest(project_dir.to_string_lossy().as_bytes());
+    format!("{name}-{}", hex(&digest[..6]))
+}
+
+/// Cache directory of the project in `project_dir`.
+pub fn project_cache_dir(project_dir: &Path) -> PathBuf {
+    cache_root().join(project_key(project_dir))
+}
+
+/// A cache entry as found on disk.
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    /// Name of the project's cache directory, see [`project_key`]
+    pub project: String,
+    /// Key of the entry
+    pub key: String,
+    /// Directory holding the entry
+    pub dir: PathBuf,
+    /// Cached container directories and the size of their archives
+    pub archives: Vec<(String, u64)>,
+    /// Total size of the entry in bytes
+    pub size: u64,
+    /// Last time a run restored or saved the entry
+    pub last_used: SystemTime,
+}
+
+/// Project cache directories below the cache root, sorted by name.
+pub fn list_projects(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut projects = Vec::new();
+    for entry in read_dir_if_exists(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            projects.push(entry.path());
+        }
+    }
+    projects.sort();
+    Ok(projects)
+}
+
+/// Entries of a project's cache, most recently used first.
+pub fn list_entries(cache_dir: &Path) -> std::io::Result<Vec<EntryInfo>> {
+    let project = cache_dir
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut entries = Vec::new();
+    for entry in read_dir_if_exists(cache_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let dir = entry.path();
+        let mut archives = Vec::new();
+        let mut size = 0;
+        collect_archives(&dir, &dir, &mut archives, &mut size)?;
+        archives.sort();
+
+        // Entries written before the marker existed fall back to the directory's time
+        let last_used = std::fs::metadata(dir.join(LAST_USED))
+            .or_else(|_| entry.metadata())
+            .and_then(|m| m.modified())?;
+
+        entries.push(EntryInfo {
+            project: project.clone(),
+            key: entry.file_name().to_string_lossy().into_owned(),
+            dir,
+            archives,
+            size,
+            last_used,
+        });
+    }
+    entries.sort_by(|a, b| b.last_used.cmp(&a.last_used).then(a.key.cmp(&b.key)));
+    Ok(entries)
+}
+
+fn read_dir_if_exists(dir: &Path) -> std::io::Result<Vec<std::io::Result<std::fs::DirEntry>>> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sum the size of all files below `dir` and list the archives by container path.
+fn collect_archives(
+    root: &Path,
+    dir: &Path,
+    archives: &mut Vec<(String, u64)>,
+    size: &mut u64,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_archives(root, &path, archives, size)?;
+            continue;
+        }
+        let len = entry.metadata()?.len();
+        *size += len;
+
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        if let Some(container_path) = relative.strip_suffix(".tar") {
+            archives.push((format!("/{container_path}"), len));
+        }
+    }
+    Ok(())
+}
+
+/// Entries to evict: those unused for longer than `max_age`, then the least recently used
+/// ones until the rest fits into `max_size` bytes.
+pub fn select_for_pruning(
+    entries: &[EntryInfo],
+    now: SystemTime,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i].last_used);
+
+    let mut evict = vec![false; entries.len()];
+    if let Some(max_age) = max_age {
+        for &i in &order {
+            let age = now.duration_since(entries[i].last_used).unwrap_or_default();
+            evict[i] = age > max_age;
+        }
+    }
+    if let Some(max_size) = max_size {
+        let mut total: u64 = (0..entries.len())
+            .filter(|&i| !evict[i])
+            .map(|i| entries[i].size)
+            .sum();
+        for &i in &order {
+            if total <= max_size {
+                break;
+            }
+            if !evict[i] {
+                evict[i] = true;
+                total -= entries[i].size;
+            }
+        }
+    }
+
+    order.into_iter().filter(|&i| evict[i]).collect()
+}
+
+/// Lowercase hex encoding.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_prefers_forge_then_xdg_then_home() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| PathBuf::from(v))
+            }
+        };
+
+        assert_eq!(
+            cache_root_from(env(&[
+                ("FORGE_CACHE_DIR", "/data/forge"),
+                ("HOME", "/home/me")
+            ])),
+            PathBuf::from("/data/forge")
+        );
+        assert_eq!(
+            cache_root_from(env(&[("XDG_CACHE_HOME", "/xdg"), ("HOME", "/home/me")])),
+            PathBuf::from("/xdg/forge")
+        );
+        assert_eq!(
+            cache_root_from(env(&[("XDG_CACHE_HOME", ""), ("HOME", "/home/me")])),
+            PathBuf::from("/home/me/.cache/forge")
+        );
+    }
+
+    fn config(yaml: &str) -> CacheConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn key_templates_hash_files() {
+        let project = tempfile::tempdir().unwrap();
+        std::fs::write(project.path().join("go.sum"), "v1").unwrap();
+
+        let key = render_key("go-${{ hashFiles('go.sum') }}", project.path()).unwrap();
+        assert!(key.starts_with("go-") && key.len() == 3 + 64);
+
+        std::fs::write(project.path().join("go.sum"), "v2").unwrap();
+        assert_ne!(
+            render_key("go-${{ hashFiles('go.sum') }}", project.path()).unwrap(),
+            key
+        );
+        assert_eq!(
+            render_key("go-${{ hashFiles('missing.lock') }}", project.path()).unwrap(),
+            "go-"
+        );
+        assert!(render_key("go-${{ runner.os }}", project.path()).is_err());
+    }
+
+    #[test]
+    fn entry_dirs_stay_inside_the_cache_dir() {
+        let cache = Path::new("/cache/project");
+        assert_eq!(
+            entry_dir(cache, "node/v1"),
+            Some(PathBuf::from("/cache/project/node_v1"))
+        );
+        assert_eq!(
+            entry_dir(cache, "...."),
+            Some(PathBuf::from("/cache/project/...."))
+        );
+        assert_eq!(entry_dir(cache, ".."), None);
+        assert_eq!(entry_dir(cache, "."), None);
+        assert_eq!(entry_dir(cache, ""), None);
+
+        let project = tempfile::tempdir().unwrap();
+        let result = resolve_entries(
+            &config("entries: [{key: '..', paths: [/app/node_modules]}]"),
+            project.path(),
+            cache,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn keyed_entries_come_after_the_directories() {
+        let project = tempfile::tempdir().unwrap();
+        std::fs::write(project.path().join("package-lock.json"), "{}").unwrap();
+        let cache = tempfile::tempdir().unwrap();
+
+        let entries = resolve_entries(
+            &config(
+                r#"
+enabled: true
+directories: [/root/.npm]
+entries:
+  - key: node-${{ hashFiles('package-lock.json') }}
+    paths: [/app/node_modules]
+    restore_keys: [node-]
+"#,
+            ),
+            project.path(),
+            cache.path(),
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, DEFAULT_KEY);
+        assert_eq!(entries[0].paths, ["/root/.npm"]);
+        assert!(entries[1].key.starts_with("node-") && entries[1].key.len() == 5 + 64);
+        assert_eq!(entries[1].paths, ["/app/node_modules"]);
+        assert_eq!(entries[1].restore_from, None);
+    }
+
+    #[test]
+    fn exact_hits_are_restored_but_not_saved() {
+        let project = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        std::fs::create_dir(cache.path().join("node-v1")).unwrap();
+
+        let entries = resolve_entries(
+            &config("entries: [{key: node-v1, paths: [/app/node_modules]}]"),
+            project.path(),
+            cache.path(),
+        )
+        .unwrap();
+        assert_eq!(entries[0].restore_from.as_deref(), Some("node-v1"));
+        assert!(!entries[0].save);
+    }
+
+    #[test]
+    fn misses_fall_back_to_restore_keys() {
+        let project = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        std::fs::create_dir(cache.path().join("node-old")).unwrap();
+
+        let entries = resolve_entries(
+            &config(
+                "entries: [{key: node-new, paths: [/app/node_modules], restore_keys: [npm-, node-]}]",
+            ),
+            project.path(),
+            cache.path(),
+        )
+        .unwrap();
+        assert_eq!(entries[0].key, "node-new");
+        assert_eq!(entries[0].restore_from.as_deref(), Some("node-old"));
+        assert!(entries[0].save);
+    }
+
+    #[test]
+    fn restore_keys_pick_the_last_saved_entry() {
+        let cache = tempfile::tempdir().unwrap();
+        for (key, saved_at) in [
+            ("node-b", "2025-03-01T12:00:00+00:00"),
+            ("node-a", "2025-01-01T12:00:00+00:00"),
+        ] {
+            std::fs::create_dir(cache.path().join(key)).unwrap();
+            std::fs::write(cache.path().join(key).join(SAVED_AT), saved_at).unwrap();
+        }
+        assert_eq!(
+            newest_with_prefix(cache.path(), "node-").as_deref(),
+            Some("node-b")
+        );
+
+        mark_saved(&cache.path().join("node-a"));
+        assert_eq!(
+            newest_with_prefix(cache.path(), "node-").as_deref(),
+            Some("node-a")
+        );
+    }
+
+    #[test]
+    fn saved_entries_are_restored_by_later_steps() {
+        let cache = tempfile::tempdir().unwrap();
+        let entry = ResolvedEntry {
+            key: "node-new".to_string(),
+            paths: vec!["/app/node_modules".to_string()],
+            restore_from: Some("node-old".to_string()),
+            save: true,
+        };
+        assert_eq!(entry.source(cache.path()), Some("node-old"));
+
+        let downloaded = {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("left-pad.js"), "").unwrap();
+            archive::pack_directory(dir.path(), "node_modules").unwrap()
+        };
+        let file = archive_path(cache.path(), &entry.key, "/app/node_modules");
+        store(&file, &downloaded, "/app/node_modules").unwrap();
+
+        assert!(cache.path().join("node-new/app/node_modules.tar").is_file());
+        assert_eq!(entry.source(cache.path()), Some("node-new"));
+    }
+
+    fn info(key: &str, size: u64, age_days: u64, now: SystemTime) -> EntryInfo {
+        EntryInfo {
+            project: "app-000000000000".to_string(),
+            key: key.to_string(),
+            dir: PathBuf::from(key),
+            archives: Vec::new(),
+            size,
+            last_used: now - Duration::from_secs(age_days * 86400),
+        }
+    }
+
+    #[test]
+    fn pruning_evicts_old_then_least_recently_used_entries() {
+        let now = SystemTime::now();
+        let entries = vec![
+            info("fresh", 300, 0, now),
+            info("stale", 100, 30, now),
+            info("older", 200, 5, now),
+            info("old", 200, 3, now),
+        ];
+        let keys = |selected: Vec<usize>| -> Vec<&str> {
+            selected.iter().map(|&i| entries[i].key.as_str()).collect()
+        };
+
+        let week = Some(Duration::from_secs(7 * 86400));
+        assert_eq!(
+            keys(select_for_pruning(&entries, now, week, None)),
+            ["stale"]
+        );
+        assert_eq!(
+            keys(select_for_pruning(&entries, now, None, Some(400))),
+            ["stale", "older", "old"]
+        );
+        assert_eq!(
+            keys(select_for_pruning(&entries, now, week, Some(600))),
+            ["stale", "older"]
+        );
+        assert!(select_for_pruning(&entries, now, None, None).is_empty());
+    }
+
+    #[test]
+    fn lists_entries_with_archives_and_last_use() {
+        let cache = tempfile::tempdir().unwrap();
+        let file = archive_path(cache.path(), "go-abc", "/go/pkg/mod");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, [0; 512]).unwrap();
+        std::fs::create_dir(cache.path().join("default")).unwrap();
+        touch(&cache.path().join("go-abc"));
+
+        let entries = list_entries(cache.path()).unwrap();
+        assert_eq!(entries.len(), 2);
+        let go = entries.iter().find(|e| e.key == "go-abc").unwrap();
+        assert_eq!(go.archives, vec![("/go/pkg/mod".to_string(), 512)]);
+        assert_eq!(go.size, 512);
+        assert!(
+            list_entries(&cache.path().join("missing"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn project_key_is_readable_and_unique_per_path() {
+        let a = project_key(Path::new("/src/team-a/my app"));
+        let b = project_key(Path::new("/src/team-b/my app"));
+        assert!(a.starts_with("my_app-"));
+        assert_ne!(a, b);
+        assert_eq!(a, project_key(Path::new("/src/team-a/my app")));
+    }
+}
@@ -0,0 +1,427 @@
+//! The expression language of `if:` conditions.
+//!
+//! ```text
+//! success() && git.branch == 'main'
+//! failure() || always()
+//! env.DEPLOY != 'false' && changed('src/**', 'Cargo.lock')
+//! ```
+//!
+//! Expressions combine function calls, properties (`env.NAME`, `git.branch`) and string or
+//! boolean literals with `==`, `!=`, `!`, `&&`, `||` and parentheses. They may be wrapped
+//! in `${{ }}`. Unless an expression calls `success()`, `failure()` or `always()` it only
+//! holds when everything before it succeeded, as if it were `success() && (...)`.
+
+/// A parsed condition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    expr: Expr,
+    /// Whether the expression decides about upstream failures itself
+    checks_status: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Bool(bool),
+    Str(String),
+    Property(Property),
+    Call(Function, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Property {
+    Env(String),
+    Branch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Success,
+    Failure,
+    Always,
+    Changed,
+}
+
+/// What a condition can look at when it is evaluated.
+pub struct Context<'a> {
+    /// Everything the step or stage depends on succeeded
+    pub success: bool,
+    /// Something the step or stage depends on failed
+    pub failure: bool,
+    /// Value of an environment variable
+    pub env: &'a dyn Fn(&str) -> Option<String>,
+    /// Current git branch
+    pub branch: &'a dyn Fn() -> Option<String>,
+    /// Whether a changed file matches one of the glob patterns
+    pub changed: &'a dyn Fn(&[String]) -> bool,
+}
+
+/// Result of evaluating a sub-expression.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bool(bool),
+    Str(String),
+    Null,
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            Value::Str(s) => !s.is_empty(),
+            Value::Null => false,
+        }
+    }
+
+    fn into_string(self) -> String {
+        match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Str(s) => s,
+            Value::Null => String::new(),
+        }
+    }
+}
+
+impl Condition {
+    /// Parse an expression, reporting syntax errors and unknown names.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let trimmed = text.trim();
+        let inner = trimmed
+            .strip_prefix("${{")
+            .and_then(|t| t.strip_suffix("}}"))
+            .unwrap_or(trimmed);
+
+        let tokens = tokenize(inner)?;
+        if tokens.is_empty() {
+            return Err("empty expression".to_string());
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {token}"));
+        }
+        let checks_status = calls_status(&expr);
+        Ok(Condition {
+            expr,
+            checks_status,
+        })
+    }
+
+    /// Whether the step or stage should run.
+    pub fn evaluate(&self, ctx: &Context<'_>) -> bool {
+        (self.checks_status || ctx.success) && eval(&self.expr, ctx).truthy()
+    }
+}
+
+fn calls_status(expr: &Expr) -> bool {
+    match expr {
+        Expr::Call(Function::Success | Function::Failure | Function::Always, _) => true,
+        Expr::Call(_, args) => args.iter().any(calls_status),
+        Expr::Not(e) => calls_status(e),
+        Expr::And(a, b) | Expr::Or(a, b) | Expr::Eq(a, b) | Expr::Ne(a, b) => {
+            calls_status(a) || calls_status(b)
+        }
+        Expr::Bool(_) | Expr::Str(_) | Expr::Property(_) => false,
+    }
+}
+
+fn eval(expr: &Expr, ctx: &Context<'_>) -> Value {
+    match expr {
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Str(s) => Value::Str(s.clone()),
+        Expr::Property(Property::Env(name)) => (ctx.env)(name).map_or(Value::Null, Value::Str),
+        Expr::Property(Property::Branch) => (ctx.branch)().map_or(Value::Null, Value::Str),
+        Expr::Call(Function::Success, _) => Value::Bool(ctx.success),
+        Expr::Call(Function::Failure, _) => Value::Bool(ctx.failure),
+        Expr::Call(Function::Always, _) => Value::Bool(true),
+        Expr::Call(Function::Changed, args) => {
+            let patterns: Vec<String> = args.iter().map(|a| eval(a, ctx).into_string()).collect();
+            Value::Bool((ctx.changed)(&patterns))
+        }
+        Expr::Not(e) => Value::Bool(!eval(e, ctx).truthy()),
+        Expr::And(a, b) => Value::Bool(eval(a, ctx).truthy() && eval(b, ctx).truthy()),
+        Expr::Or(a, b) => Value::Bool(eval(a, ctx).truthy() || eval(b, ctx).truthy()),
+        Expr::Eq(a, b) => Value::Bool(eval(a, ctx).into_string() == eval(b, ctx).into_string()),
+        Expr::Ne(a, b) => Value::Bool(eval(a, ctx).into_string() != eval(b, ctx).into_string()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    Comma,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "'{name}'"),
+            Token::Str(s) => write!(f, "string '{s}'"),
+            Token::LParen => f.write_str("'('"),
+            Token::RParen => f.write_str("')'"),
+            Token::Comma => f.write_str("','"),
+            Token::Not => f.write_str("'!'"),
+            Token::And => f.write_str("'&&'"),
+            Token::Or => f.write_str("'||'"),
+            Token::Eq => f.write_str("'=='"),
+            Token::Ne => f.write_str("'!='"),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Comma,
+                });
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => value.push(ch),
+                        None => return Err(format!("unterminated string {c}{value}")),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            '!' | '=' | '&' | '|' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let token = match (c, next) {
+                    ('!', Some('=')) => Token::Ne,
+                    ('!', _) => Token::Not,
+                    ('=', Some('=')) => Token::Eq,
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    _ => return Err(format!("unexpected '{c}', did you mean '{c}{c}'?")),
+                };
+                if token != Token::Not {
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_alphanumeric() || matches!(ch, '_' | '-' | '.') {
+                        name.push(ch);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(name));
+            }
+            _ => return Err(format!("unexpected character '{c}'")),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected {expected}, found {token}")),
+            None => Err(format!("expected {expected} at end of expression")),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        let left = self.primary()?;
+        match self.peek() {
+            Some(Token::Eq) => {
+                self.next();
+                Ok(Expr::Eq(Box::new(left), Box::new(self.primary()?)))
+            }
+            Some(Token::Ne) => {
+                self.next();
+                Ok(Expr::Ne(Box::new(left), Box::new(self.primary()?)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Expr::Str(s)),
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::LParen) => {
+                self.next();
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::RParen) {
+                    loop {
+                        args.push(self.or()?);
+                        if self.peek() != Some(&Token::Comma) {
+                            break;
+                        }
+                        self.next();
+                    }
+                }
+                self.expect(Token::RParen)?;
+                call(&name, args)
+            }
+            Some(Token::Ident(name)) => property(&name),
+            Some(token) => Err(format!("unexpected {token}")),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn call(name: &str, args: Vec<Expr>) -> Result<Expr, String> {
+    let function = match name {
+        "success" => Function::Success,
+        "failure" => Function::Failure,
+        "always" => Function::Always,
+        "changed" => Function::Changed,
+        _ => {
+            return Err(format!(
+                "unknown function '{name}' (expected success(), failure(), always() or changed())"
+            ));
+        }
+    };
+    match (function, args.len()) {
+        (Function::Changed, 0) => Err("changed() needs at least one path or glob".to_string()),
+        (Function::Changed, _) | (_, 0) => Ok(Expr::Call(function, args)),
+        _ => Err(format!("{name}() takes no arguments")),
+    }
+}
+
+fn property(name: &str) -> Result<Expr, String> {
+    match name {
+        "true" => Ok(Expr::Bool(true)),
+        "false" => Ok(Expr::Bool(false)),
+        "branch" | "git.branch" => Ok(Expr::Property(Property::Branch)),
+        _ => match name.strip_prefix("env.") {
+            Some(var) if !var.is_empty() => Ok(Expr::Property(Property::Env(var.to_string()))),
+            _ => Err(format!(
+                "unknown name '{name}' (expected env.NAME, git.branch, true or false)"
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(text: &str, success: bool, failure: bool) -> bool {
+        let env = |name: &str| (name == "DEPLOY").then(|| "yes".to_string());
+        let branch = || Some("main".to_string());
+        let changed = |patterns: &[String]| patterns.iter().any(|p| p == "src/**");
+        let ctx = Context {
+            success,
+            failure,
+            env: &env,
+            branch: &branch,
+            changed: &changed,
+        };
+        Condition::parse(text).unwrap().evaluate(&ctx)
+    }
+
+    #[test]
+    fn status_functions() {
+        assert!(run("success()", true, false));
+        assert!(!run("success()", false, true));
+        assert!(run("failure()", false, true));
+        assert!(run("always()", false, true));
+        assert!(run("${{ failure() || always() }}", false, false));
+    }
+
+    #[test]
+    fn conditions_without_status_functions_require_success() {
+        assert!(run("git.branch == 'main'", true, false));
+        assert!(!run("git.branch == 'main'", false, true));
+        assert!(run("always() && branch == 'main'", false, true));
+    }
+
+    #[test]
+    fn operators_and_properties() {
+        assert!(run(
+            "env.DEPLOY == 'yes' && !(branch != \"main\")",
+            true,
+            false
+        ));
+        assert!(!run("env.MISSING", true, false));
+        assert!(run("env.MISSING == ''", true, false));
+        assert!(run("changed('docs/**') || changed('src/**')", true, false));
+        assert!(run("false || true && true", true, false));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(Condition::parse("").is_err());
+        assert!(Condition::parse("success(").is_err());
+        assert!(Condition::parse("branch = 'main'").is_err());
+        assert!(Condition::parse("deploy()").is_err());
+        assert!(Condition::parse("runner.os == 'Linux'").is_err());
+        assert!(Condition::parse("changed()").is_err());
+        assert!(Condition::parse("'main").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Information about the git checkout a pipeline runs in.
+//!
+//! Everything is looked up lazily with the `git` command, so projects that are not git
+//! repositories, or hosts without git, simply have no branch and no changed files.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Git state of the project directory, queried on first use.
+pub struct GitInfo {
+    dir: PathBuf,
+    /// Revision that `changed()` compares the working tree with
+    base: String,
+    branch: OnceLock<Option<String>>,
+    sha: OnceLock<Option<String>>,
+    changed: OnceLock<Vec<String>>,
+}
+
+impl GitInfo {
+    /// Git state of `dir`; files count as changed relative to `base`, e.g. `HEAD`.
+    pub fn new(dir: &Path, base: &str) -> Self {
+        GitInfo {
+            dir: dir.to_path_buf(),
+            base: base.to_string(),
+            branch: OnceLock::new(),
+            sha: OnceLock::new(),
+            changed: OnceLock::new(),
+        }
+    }
+
+    /// Current branch, `None` for a detached HEAD or outside of a repository.
+    pub fn branch(&self) -> Option<String> {
+        self.branch
+            .get_or_init(|| {
+                git(&self.dir, &["rev-parse", "--abbrev-ref", "HEAD"])
+                    .and_then(|out| out.lines().next().map(str::to_string))
+                    .filter(|branch| branch != "HEAD")
+            })
+            .clone()
+    }
+
+    /// Full commit hash of `HEAD`, `None` outside of a repository or before the first commit.
+    pub fn sha(&self) -> Option<String> {
+        self.sha
+            .get_or_init(|| {
+                git(&self.dir, &["rev-parse", "HEAD"])
+                    .and_then(|out| out.lines().next().map(str::to_string))
+            })
+            .clone()
+    }
+
+    /// Files that differ from the base revision, including untracked ones, relative to the
+    /// project directory. The run records in `.forge` never count.
+    pub fn changed_files(&self) -> &[String] {
+        self.changed.get_or_init(|| {
+            let mut files: Vec<String> = Vec::new();
+            let outputs = [
+                git(
+                    &self.dir,
+                    &["diff", "--name-only", "--relative", &self.base],
+                ),
+                git(&self.dir, &["ls-files", "--others", "--exclude-standard"]),
+            ];
+            let records = format!("{}/", crate::runs::FORGE_DIR);
+            for output in outputs.into_iter().flatten() {
+                files.extend(
+                    output
+                        .lines()
+                        .filter(|file| !file.starts_with(&records))
+                        .map(str::to_string),
+                );
+            }
+            files.sort();
+            files.dedup();
+            files
+        })
+    }
+
+    /// Whether a changed file matches one of the glob patterns.
+    pub fn changed(&self, patterns: &[String]) -> bool {
+        self.changed_files().iter().any(|file| {
+            patterns.iter().any(|pattern| {
+                let pattern = pattern.trim_start_matches("./");
+                crate::glob::matches(pattern, file)
+                    || file.starts_with(&format!("{}/", pattern.trim_end_matches('/')))
+            })
+        })
+    }
+}
+
+/// Output of a successful git command.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outside_a_repository_nothing_is_known() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = GitInfo::new(dir.path(), "HEAD");
+        assert_eq!(info.branch(), None);
+        assert_eq!(info.sha(), None);
+        assert!(info.changed_files().is_empty());
+    }
+
+    #[test]
+    fn directories_match_the_files_below_them() {
+        let info = GitInfo::new(Path::new("."), "HEAD");
+        info.changed
+            .set(vec!["src/main.rs".to_string(), "README.md".to_string()])
+            .unwrap();
+        assert!(info.changed(&["src".to_string()]));
+        assert!(info.changed(&["./src/*.rs".to_string()]));
+        assert!(info.changed(&["*.md".to_string()]));
+        assert!(!info.changed(&["docs/**".to_string()]));
+    }
+
+    #[test]
+    fn run_records_are_not_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        if git(dir.path(), &["init", "--quiet"]).is_none() {
+            return;
+        }
+        let records = dir.path().join(".forge/runs/1");
+        std::fs::create_dir_all(&records).unwrap();
+        std::fs::write(records.join("run.json"), "{}").unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/forge.json"), "{}").unwrap();
+
+        let info = GitInfo::new(dir.path(), "HEAD");
+        assert_eq!(info.changed_files(), ["src/forge.json"]);
+    }
+}
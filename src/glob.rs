@@ -0,0 +1,127 @@
+//! Minimal glob matching for `/`-separated paths.
+//!
+//! Supports `*` (anything but `/`), `?` (one character but `/`) and `**` as a whole path
+//! segment (any number of segments, including none).
+
+use std::path::{Path, PathBuf};
+
+/// Whether `path` matches `pattern`. Both use `/` as separator.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match_segments(&pattern, &path)
+}
+
+/// Whether `text` contains glob metacharacters.
+pub fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?'])
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                match_segment(first.as_bytes(), segment.as_bytes())
+                    && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| match_segment(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && match_segment(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && match_segment(rest, &text[1..]),
+    }
+}
+
+/// Files below `root` whose path relative to `root` matches `pattern`, sorted.
+///
+/// `.git` directories are not searched, nor the run records in `.forge` directly below
+/// `root`.
+pub fn find_files(root: &Path, pattern: &str) -> std::io::Result<Vec<PathBuf>> {
+    fn walk(
+        root: &Path,
+        dir: &Path,
+        pattern: &str,
+        found: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                let name = entry.file_name();
+                let records = dir == root && name == crate::runs::FORGE_DIR;
+                if name != ".git" && !records {
+                    walk(root, &path, pattern, found)?;
+                }
+            } else if let Ok(relative) = path.strip_prefix(root) {
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                if matches(pattern, &relative) {
+                    found.push(path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = Vec::new();
+    walk(root, root, pattern, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards_within_a_segment() {
+        assert!(matches("*.lock", "Cargo.lock"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/tool.rs"));
+        assert!(matches("go.su?", "go.sum"));
+        assert!(!matches("go.su?", "go.mod.sum"));
+    }
+
+    #[test]
+    fn double_star_spans_segments() {
+        assert!(matches("**/go.sum", "go.sum"));
+        assert!(matches("**/go.sum", "tools/lint/go.sum"));
+        assert!(matches("src/**", "src/a/b/c.rs"));
+        assert!(!matches("src/**/*.rs", "tests/a.rs"));
+    }
+
+    #[test]
+    fn finds_files_recursively() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("web/app")).unwrap();
+        std::fs::write(dir.path().join("package-lock.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("web/app/package-lock.json"), "{}").unwrap();
+
+        let found = find_files(dir.path(), "**/package-lock.json").unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            find_files(dir.path(), "package-lock.json").unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn skips_run_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = crate::runs::run_dir(dir.path(), "20240501-142233-3f9a1c").join("logs");
+        std::fs::create_dir_all(&logs).unwrap();
+        std::fs::write(logs.join("build.log"), "ok").unwrap();
+        std::fs::write(dir.path().join("build.log"), "ok").unwrap();
+
+        let found = find_files(dir.path(), "**/*.log").unwrap();
+        assert_eq!(found, [dir.path().join("build.log")]);
+    }
+}
@@ -0,0 +1,348 @@
+//! Dependency graph used to order and schedule stages and the steps inside them.
+//!
+//! Nodes are identified by their position in the configuration, which keeps the
+//! YAML order as the tie-breaker whenever several nodes are ready at the same time.
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+
+/// Problems found while building a dependency graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// Two nodes share the same name, so dependencies on it are ambiguous.
+    Duplicate(String),
+    /// A node depends on a name that is not part of the graph.
+    UnknownDependency { node: String, dependency: String },
+    /// The nodes depend on each other in a loop; the first node is repeated at the end.
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::Duplicate(name) => write!(f, "'{name}' is defined more than once"),
+            GraphError::UnknownDependency { node, dependency } => {
+                write!(f, "'{node}' depends on unknown '{dependency}'")
+            }
+            GraphError::Cycle(path) => write!(f, "circular dependency {}", path.join(" -> ")),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// Final state of a node once the graph has been executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+/// Result of running a single node.
+#[derive(Debug)]
+pub enum Outcome<E> {
+    Succeeded,
+    Failed(E),
+    Skipped,
+}
+
+impl<E> Outcome<E> {
+    pub fn status(&self) -> Status {
+        match self {
+            Outcome::Succeeded => Status::Succeeded,
+            Outcome::Failed(_) => Status::Failed,
+            Outcome::Skipped => Status::Skipped,
+        }
+    }
+}
+
+/// Directed acyclic graph of named nodes and the nodes they depend on.
+#[derive(Debug)]
+pub struct DependencyGraph {
+    names: Vec<String>,
+    dependencies: Vec<Vec<usize>>,
+}
+
+impl DependencyGraph {
+    /// Build a graph from `(name, depends_on)` pairs.
+    ///
+    /// Fails if a name is used twice, if a dependency names an unknown node or if the
+    /// dependencies contain a cycle. Unnamed nodes cannot be depended on and may repeat.
+    pub fn new<'a, I>(nodes: I) -> Result<Self, GraphError>
+    where
+        I: IntoIterator<Item = (&'a str, &'a [String])>,
+    {
+        let nodes: Vec<(&str, &[String])> = nodes.into_iter().collect();
+
+        let mut index = HashMap::new();
+        for (i, (name, _)) in nodes.iter().enumerate() {
+            if name.is_empty() {
+                continue;
+            }
+            if index.insert(*name, i).is_some() {
+                return Err(GraphError::Duplicate(name.to_string()));
+            }
+        }
+
+        let mut dependencies = Vec::with_capacity(nodes.len());
+        for (name, depends_on) in &nodes {
+            let mut resolved = Vec::with_capacity(depends_on.len());
+            for dependency in depends_on.iter() {
+                match index.get(dependency.as_str()) {
+                    Some(&i) => resolved.push(i),
+                    None => {
+                        return Err(GraphError::UnknownDependency {
+                            node: name.to_string(),
+                            dependency: dependency.clone(),
+                        });
+                    }
+                }
+            }
+            dependencies.push(resolved);
+        }
+
+        let graph = DependencyGraph {
+            names: nodes.iter().map(|(name, _)| name.to_string()).collect(),
+            dependencies,
+        };
+        if let Some(cycle) = graph.find_cycle() {
+            return Err(GraphError::Cycle(cycle));
+        }
+        Ok(graph)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn name(&self, node: usize) -> &str {
+        &self.names[node]
+    }
+
+    /// Direct dependencies of a node.
+    pub fn dependencies(&self, node: usize) -> &[usize] {
+        &self.dependencies[node]
+    }
+
+    /// Find a dependency loop, returned as the path of names that leads back to its start.
+    fn find_cycle(&self) -> Option<Vec<String>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            New,
+            OnPath,
+            Done,
+        }
+
+        fn visit(
+            graph: &DependencyGraph,
+            node: usize,
+            marks: &mut [Mark],
+            path: &mut Vec<usize>,
+        ) -> Option<Vec<String>> {
+            marks[node] = Mark::OnPath;
+            path.push(node);
+            for &dep in &graph.dependencies[node] {
+                match marks[dep] {
+                    Mark::OnPath => {
+                        let start = path.iter().position(|&n| n == dep).unwrap_or(0);
+                        let mut cycle: Vec<String> = path[start..]
+                            .iter()
+                            .map(|&n| graph.names[n].clone())
+                            .collect();
+                        cycle.push(graph.names[dep].clone());
+                        return Some(cycle);
+                    }
+                    Mark::New => {
+                        if let Some(cycle) = visit(graph, dep, marks, path) {
+                            return Some(cycle);
+                        }
+                    }
+                    Mark::Done => {}
+                }
+            }
+            path.pop();
+            marks[node] = Mark::Done;
+            None
+        }
+
+        let mut marks = vec![Mark::New; self.len()];
+        let mut path = Vec::new();
+        (0..self.len()).find_map(|node| {
+            if marks[node] == Mark::New {
+                visit(self, node, &mut marks, &mut path)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Order the nodes so that every node comes after its dependencies.
+    ///
+    /// Among nodes that are ready at the same time the one declared first wins.
+    pub fn topological_order(&self) -> Vec<usize> {
+        let mut remaining: Vec<usize> = self.dependencies.iter().map(Vec::len).collect();
+        let mut done = vec![false; self.len()];
+        let mut order = Vec::with_capacity(self.len());
+
+        // Graphs are checked for cycles when built, so a ready node always exists
+        while let Some(next) = (0..self.len()).find(|&i| !done[i] && remaining[i] == 0) {
+            done[next] = true;
+            order.push(next);
+            for (i, deps) in self.dependencies.iter().enumerate() {
+                remaining[i] -= deps.iter().filter(|&&d| d == next).count();
+            }
+        }
+
+        order
+    }
+
+    /// Whether the graph is a single chain, i.e. no two nodes can ever run at the same time.
+    pub fn is_chain(&self) -> bool {
+        let mut depth = vec![0; self.len()];
+        for node in self.topological_order() {
+            depth[node] = self.dependencies[node]
+                .iter()
+                .map(|&d| depth[d] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+
+        let mut seen = vec![false; self.len()];
+        depth
+            .iter()
+            .all(|&d| !std::mem::replace(&mut seen[d], true))
+    }
+}
+
+/// Run every node of the graph, starting each one as soon as its dependencies have settled.
+///
+/// `run` receives the node and the status of each of its dependencies (in the order of
+/// [`DependencyGraph::dependencies`]) and decides for itself whether to run or skip.
+pub async fn execute<E, F, Fut>(graph: &DependencyGraph, mut run: F) -> Vec<Outcome<E>>
+where
+    F: FnMut(usize, Vec<Status>) -> Fut,
+    Fut: Future<Output = Outcome<E>>,
+{
+    let order = graph.topological_order();
+
+    let mut outcomes: Vec<Option<Outcome<E>>> = (0..graph.len()).map(|_| None).collect();
+    let mut started = vec![false; graph.len()];
+    let mut running = FuturesUnordered::new();
+
+    loop {
+        for &node in &order {
+            if started[node] {
+                continue;
+            }
+            let upstream: Option<Vec<Status>> = graph
+                .dependencies(node)
+                .iter()
+                .map(|&d| outcomes[d].as_ref().map(Outcome::status))
+                .collect();
+            if let Some(upstream) = upstream {
+                started[node] = true;
+                let future = run(node, upstream);
+                running.push(async move { (node, future.await) });
+            }
+        }
+
+        match running.next().await {
+            Some((node, outcome)) => outcomes[node] = Some(outcome),
+            None => break,
+        }
+    }
+
+    outcomes
+        .into_iter()
+        .map(|outcome| outcome.unwrap_or(Outcome::Skipped))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(nodes: &[(&str, &[&str])]) -> Result<DependencyGraph, GraphError> {
+        let owned: Vec<(&str, Vec<String>)> = nodes
+            .iter()
+            .map(|(name, deps)| (*name, deps.iter().map(|d| d.to_string()).collect()))
+            .collect();
+        DependencyGraph::new(owned.iter().map(|(name, deps)| (*name, deps.as_slice())))
+    }
+
+    #[test]
+    fn orders_dependencies_first_and_keeps_declaration_order() {
+        let g = graph(&[("test", &["setup"]), ("setup", &[]), ("lint", &[])]).unwrap();
+        assert_eq!(g.topological_order(), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn rejects_unknown_dependency() {
+        let err = graph(&[("test", &["build"])]).unwrap_err();
+        assert_eq!(
+            err,
+            GraphError::UnknownDependency {
+                node: "test".into(),
+                dependency: "build".into()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let err = graph(&[("a", &[]), ("a", &[])]).unwrap_err();
+        assert_eq!(err, GraphError::Duplicate("a".into()));
+        assert!(graph(&[("", &[]), ("", &[])]).is_ok());
+    }
+
+    #[test]
+    fn reports_the_cycle_path() {
+        let err = graph(&[("c", &[]), ("a", &["b"]), ("b", &["d"]), ("d", &["a"])]).unwrap_err();
+        assert_eq!(
+            err,
+            GraphError::Cycle(vec!["a".into(), "b".into(), "d".into(), "a".into()])
+        );
+        assert_eq!(err.to_string(), "circular dependency a -> b -> d -> a");
+
+        let err = graph(&[("a", &["a"])]).unwrap_err();
+        assert_eq!(err, GraphError::Cycle(vec!["a".into(), "a".into()]));
+    }
+
+    #[test]
+    fn detects_chains() {
+        assert!(
+            graph(&[("a", &[]), ("b", &["a"]), ("c", &["b"])])
+                .unwrap()
+                .is_chain()
+        );
+        assert!(
+            !graph(&[("a", &[]), ("b", &["a"]), ("c", &["a"])])
+                .unwrap()
+                .is_chain()
+        );
+        assert!(!graph(&[("a", &[]), ("b", &[])]).unwrap().is_chain());
+    }
+
+    #[tokio::test]
+    async fn execute_passes_dependency_status_downstream() {
+        let g = graph(&[("a", &[]), ("b", &["a"]), ("c", &[])]).unwrap();
+        let outcomes = execute(&g, |node, upstream| async move {
+            if upstream.iter().any(|s| *s != Status::Succeeded) {
+                Outcome::Skipped
+            } else if node == 0 {
+                Outcome::Failed("boom")
+            } else {
+                Outcome::Succeeded
+            }
+        })
+        .await;
+        let statuses: Vec<Status> = outcomes.iter().map(Outcome::status).collect();
+        assert_eq!(
+            statuses,
+            vec![Status::Failed, Status::Skipped, Status::Succeeded]
+        );
+    }
+}
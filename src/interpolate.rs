@@ -0,0 +1,307 @@
+//! Interpolation of variables into step fields.
+//!
+//! `${{ ... }}` references work in a step's `image`, `command`, `working_dir` and `env`
+//! values:
+//!
+//! - `vars.NAME`: the `variables` block, overridden by `forge-cli run --var NAME=value`
+//! - `env.NAME`: the top-level `env` block, then the host environment
+//! - `forge.run_id`, `forge.project_dir`, `git.sha`, `git.branch`: built-in values
+//!
+//! `${NAME}` is a shorthand that looks `NAME` up in the variables, the top-level `env` and
+//! the host environment. It is not expanded in `command`, where the container's shell
+//! expands it. `$${` escapes either form.
+//!
+//! Step output references (`${{ steps.NAME.outputs.KEY }}`) are left for the runner, which
+//! resolves them when the step starts.
+
+use crate::ForgeConfig;
+use std::collections::HashMap;
+
+/// Everything references can resolve to.
+pub struct Scope<'a> {
+    /// The `variables` block with `--var` overrides applied
+    pub vars: &'a HashMap<String, String>,
+    /// The top-level `env` block
+    pub env: &'a HashMap<String, String>,
+    /// Host environment variables
+    pub host: &'a dyn Fn(&str) -> Option<String>,
+    /// Built-in values such as `forge.run_id`
+    pub builtins: &'a dyn Fn(&str) -> Option<String>,
+}
+
+/// Names of the built-in values.
+pub const BUILTINS: [&str; 4] = ["forge.run_id", "forge.project_dir", "git.sha", "git.branch"];
+
+impl Scope<'_> {
+    /// Value of a `${{ ... }}` reference.
+    fn lookup(&self, reference: &str) -> Option<String> {
+        if let Some(name) = reference.strip_prefix("vars.") {
+            self.vars.get(name).cloned()
+        } else if let Some(name) = reference.strip_prefix("env.") {
+            self.env.get(name).cloned().or_else(|| (self.host)(name))
+        } else if BUILTINS.contains(&reference) {
+            (self.builtins)(reference)
+        } else {
+            None
+        }
+    }
+
+    /// Value of a `${NAME}` reference.
+    fn variable(&self, name: &str) -> Option<String> {
+        self.vars
+            .get(name)
+            .or_else(|| self.env.get(name))
+            .cloned()
+            .or_else(|| (self.host)(name))
+    }
+
+    /// Expand the references in `text`, also `${NAME}` ones if `shell_style` is set.
+    /// References that cannot be resolved are left in place and added to `unresolved`.
+    pub fn expand(&self, text: &str, shell_style: bool, unresolved: &mut Vec<String>) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            result.push_str(&rest[..start]);
+            let tail = &rest[start..];
+
+            // `$${` stands for a literal `${`
+            if rest[..start].ends_with('$') {
+                result.push('{');
+                rest = &tail[2..];
+                continue;
+            }
+
+            let (reference, value, len) = if let Some(inner) = tail.strip_prefix("${{") {
+                let Some(end) = inner.find("}}") else {
+                    rest = tail;
+                    break;
+                };
+                let name = inner[..end].trim();
+                if crate::outputs::parse_reference(name).is_some() {
+                    result.push_str(&tail[..end + 5]);
+                    rest = &tail[end + 5..];
+                    continue;
+                }
+                (&tail[..end + 5], self.lookup(name), end + 5)
+            } else if shell_style {
+                let Some(end) = tail.find('}') else {
+                    rest = tail;
+                    break;
+                };
+                let name = &tail[2..end];
+                let valid =
+                    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                let value = if valid { self.variable(name) } else { None };
+                (&tail[..end + 1], value, end + 1)
+            } else {
+                result.push_str("${");
+                rest = &tail[2..];
+                continue;
+            };
+
+            match value {
+                Some(value) => result.push_str(&value),
+                None => {
+                    unresolved.push(reference.to_string());
+                    result.push_str(reference);
+                }
+            }
+            rest = &tail[len..];
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+/// Expand references in the top-level `env`, the `env` of stage defaults and every step of the
+/// configuration, and add the top-level `env` to each step's environment. `overrides` are the
+/// `--var` arguments.
+///
+/// All unresolved references are reported together.
+pub fn apply(
+    config: &mut ForgeConfig,
+    overrides: &[(String, String)],
+    builtins: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut vars = config.variables.clone();
+    vars.extend(overrides.iter().cloned());
+    let host = |name: &str| std::env::var(name).ok();
+    let mut problems = Vec::new();
+
+    // The top-level env can use variables, built-in values and the host environment
+    let mut env = config.env.clone();
+    let scope = Scope {
+        vars: &vars,
+        env: &HashMap::new(),
+        host: &host,
+        builtins,
+    };
+    let mut unresolved = Vec::new();
+    for value in env.values_mut() {
+        *value = scope.expand(value, true, &mut unresolved);
+    }
+    if !unresolved.is_empty() {
+        problems.push(format!("• env: {}", unresolved.join(", ")));
+    }
+
+    let scope = Scope {
+        vars: &vars,
+        env: &env,
+        host: &host,
+        builtins,
+    };
+    // Stage conditions read these
+    for stage in &mut config.stages {
+        let mut unresolved = Vec::new();
+        for value in stage.defaults.env.values_mut() {
+            *value = scope.expand(value, true, &mut unresolved);
+        }
+        if !unresolved.is_empty() {
+            problems.push(format!(
+                "• defaults of stage '{}': {}",
+                stage.name,
+                unresolved.join(", ")
+            ));
+        }
+    }
+    let steps = config
+        .stages
+        .iter_mut()
+        .flat_map(|stage| stage.steps.iter_mut())
+        .chain(config.steps.iter_mut());
+    for step in steps {
+        let mut unresolved = Vec::new();
+        step.image = scope.expand(&step.image, true, &mut unresolved);
+        step.working_dir = scope.expand(&step.working_dir, true, &mut unresolved);
+        step.command = scope.expand(&step.command, false, &mut unresolved);
+        for value in step.env.values_mut() {
+            *value = scope.expand(value, true, &mut unresolved);
+        }
+        for (name, value) in &env {
+            step.env
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+        unresolved.dedup();
+        if !unresolved.is_empty() {
+            problems.push(format!(
+                "• {}: {}",
+                step.display_name(),
+                unresolved.join(", ")
+            ));
+        }
+    }
+    config.env = env;
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(Box::new(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Configuration validation failed: unresolved references\n{}\n\
+             Hint: Define them under 'variables' or 'env', pass --var NAME=value, or set the \
+             host environment variable. Built-in values are {}. Write '$${{' for a literal '${{'",
+            problems.join("\n"),
+            BUILTINS.join(", ")
+        ),
+    )))
+}
+
+/// Parse a `--var NAME=value` argument.
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=value, got '{arg}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(text: &str, shell_style: bool) -> (String, Vec<String>) {
+        let vars = HashMap::from([("VERSION".to_string(), "1.2.3".to_string())]);
+        let env = HashMap::from([("REGISTRY".to_string(), "ghcr.io/acme".to_string())]);
+        let host = |name: &str| (name == "USER").then(|| "ci".to_string());
+        let builtins = |name: &str| (name == "git.sha").then(|| "abc123".to_string());
+        let scope = Scope {
+            vars: &vars,
+            env: &env,
+            host: &host,
+            builtins: &builtins,
+        };
+        let mut unresolved = Vec::new();
+        let expanded = scope.expand(text, shell_style, &mut unresolved);
+        (expanded, unresolved)
+    }
+
+    #[test]
+    fn expands_expressions_from_every_source() {
+        let (text, unresolved) = expand(
+            "${{ env.REGISTRY }}/app:${{vars.VERSION}}-${{ git.sha }} by ${{ env.USER }}",
+            false,
+        );
+        assert_eq!(text, "ghcr.io/acme/app:1.2.3-abc123 by ci");
+        assert!(unresolved.is_empty());
+    }
+
+    #[test]
+    fn shell_style_references_only_when_enabled() {
+        assert_eq!(
+            expand("${REGISTRY}/app:${VERSION}", true).0,
+            "ghcr.io/acme/app:1.2.3"
+        );
+        assert_eq!(
+            expand("echo ${VERSION} $HOME", false).0,
+            "echo ${VERSION} $HOME"
+        );
+    }
+
+    #[test]
+    fn reports_unresolved_references() {
+        let (text, unresolved) = expand("${{ vars.MISSING }} ${{ steps.x }} ${NOPE}", true);
+        assert_eq!(text, "${{ vars.MISSING }} ${{ steps.x }} ${NOPE}");
+        assert_eq!(
+            unresolved,
+            ["${{ vars.MISSING }}", "${{ steps.x }}", "${NOPE}"]
+        );
+    }
+
+    #[test]
+    fn leaves_step_outputs_for_the_runner() {
+        let (text, unresolved) = expand("v=${{ steps.build.outputs.version }}", false);
+        assert_eq!(text, "v=${{ steps.build.outputs.version }}");
+        assert!(unresolved.is_empty());
+    }
+
+    #[test]
+    fn unterminated_references_are_kept_as_written() {
+        assert_eq!(expand("prefix ${oops", true).0, "prefix ${oops");
+        assert_eq!(
+            expand("${VERSION} ${{ vars.VERSION", true).0,
+            "1.2.3 ${{ vars.VERSION"
+        );
+    }
+
+    #[test]
+    fn double_dollar_escapes() {
+        assert_eq!(
+            expand("$${{ vars.VERSION }}", true).0,
+            "${{ vars.VERSION }}"
+        );
+        assert_eq!(expand("$${USER}", true).0, "${USER}");
+    }
+
+    #[test]
+    fn parses_var_arguments() {
+        assert_eq!(
+            parse_var("VERSION=1.2=3").unwrap(),
+            ("VERSION".to_string(), "1.2=3".to_string())
+        );
+        assert!(parse_var("VERSION").is_err());
+        assert!(parse_var("=1").is_err());
+    }
+}
@@ -1,3 +1,19 @@
+mod archive;
+mod artifacts;
+mod cache;
+mod expr;
+mod git;
+mod glob;
+mod graph;
+mod interpolate;
+mod matrix;
+mod outputs;
+mod rerun;
+mod runs;
+mod secrets;
+mod select;
+mod units;
+
 use bollard::Docker;
 use bollard::container::{Config, CreateContainerOptions};
 use bollard::image::CreateImageOptions;
@@ -5,12 +21,14 @@ use bollard::models::{HostConfig, Mount, MountTypeEnum};
 use clap::{Parser, Subcommand};
 use colored::*;
 use futures_util::stream::StreamExt;
+use graph::{DependencyGraph, GraphError, Outcome, Status};
 use indicatif::{ProgressBar, ProgressStyle};
+use secrets::{MaskedLines, Secrets};
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Step {
@@ -31,6 +49,171 @@ struct Step {
 
     #[serde(default)]
     depends_on: Vec<String>,
+
+    /// Maximum run time, e.g. `90s` or `10m`; overrides `defaults.timeout`
+    #[serde(default)]
+    timeout: Option<String>,
+
+    /// Shell running the command with `-c`, e.g. `bash` or `/bin/bash -eo pipefail`
+    #[serde(default)]
+    shell: Option<String>,
+
+    /// User (name or UID[:GID]) the command runs as inside the container
+    #[serde(default)]
+    user: Option<String>,
+
+    /// Re-run the step in a fresh container when it fails
+    #[serde(default)]
+    retry: Option<RetryPolicy>,
+
+    /// Report a failure of this step without failing the pipeline
+    #[serde(default, alias = "continue_on_error")]
+    allow_failure: bool,
+
+    /// Condition deciding whether the step runs, e.g. `failure()` or `git.branch == 'main'`
+    #[serde(default, rename = "if")]
+    condition: Option<String>,
+
+    /// Run one copy of the step per combination of values
+    #[serde(default)]
+    matrix: Option<Matrix>,
+
+    /// Files copied out of the container after the step, for later steps and inspection
+    #[serde(default)]
+    artifacts: Option<Artifacts>,
+
+    /// Artifacts of earlier steps copied into the container before the step starts
+    #[serde(default)]
+    needs_artifacts: Vec<String>,
+
+    /// Set on the copies made from a step with a matrix
+    #[serde(skip)]
+    job: Option<matrix::Job>,
+}
+
+impl Step {
+    /// Name used for the step in logs and error messages.
+    fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            "unnamed step"
+        } else {
+            &self.name
+        }
+    }
+
+    /// Name of the artifact the step produces, if any.
+    fn artifact_name(&self) -> Option<&str> {
+        let artifacts = self.artifacts.as_ref()?;
+        Some(artifacts.name.as_deref().unwrap_or(self.display_name()))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Artifacts {
+    /// Container paths or glob patterns; relative ones start at the step's working directory
+    paths: Vec<String>,
+
+    /// Name later steps refer to in `needs_artifacts`; defaults to the step name
+    #[serde(default)]
+    name: Option<String>,
+
+    /// Outcomes of the step after which the files are collected
+    #[serde(default)]
+    when: ArtifactsWhen,
+}
+
+/// When a step's artifacts are collected.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ArtifactsWhen {
+    #[default]
+    OnSuccess,
+    OnFailure,
+    Always,
+}
+
+impl ArtifactsWhen {
+    fn applies(self, success: bool) -> bool {
+        match self {
+            ArtifactsWhen::OnSuccess => success,
+            ArtifactsWhen::OnFailure => !success,
+            ArtifactsWhen::Always => true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RetryPolicy {
+    /// Total number of attempts, including the first run
+    attempts: u32,
+
+    /// Pause before the first retry, e.g. `5s`
+    #[serde(default)]
+    delay: Option<String>,
+
+    #[serde(default)]
+    backoff: Backoff,
+
+    /// Only retry failures with one of these exit codes; any failure when empty
+    #[serde(default)]
+    on_exit_codes: Vec<i64>,
+}
+
+/// How the pause between attempts grows.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Backoff {
+    /// Always wait `delay`
+    #[default]
+    Fixed,
+    /// Double the pause after every failed attempt
+    Exponential,
+}
+
+impl RetryPolicy {
+    /// Parsed `delay`, checked together with `attempts`. `scope` names the step in errors.
+    fn delay(
+        &self,
+        scope: &str,
+    ) -> Result<std::time::Duration, Box<dyn std::error::Error + Send + Sync>> {
+        let invalid = |reason: String, hint: &str| -> Box<dyn std::error::Error + Send + Sync> {
+            Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Configuration validation failed: {scope}: {reason}\nHint: {hint}"),
+            ))
+        };
+        if self.attempts == 0 {
+            return Err(invalid(
+                "retry attempts must be at least 1".to_string(),
+                "'attempts' counts the first run, e.g. 'attempts: 3' retries twice",
+            ));
+        }
+        match self.delay.as_deref() {
+            None => Ok(std::time::Duration::ZERO),
+            Some(text) => units::parse_duration(text)
+                .map_err(|e| invalid(e, "Use a duration like '5s' or '1m' for the retry delay")),
+        }
+    }
+
+    /// Pause after the given failed attempt, counting from 1.
+    fn pause_after(&self, delay: std::time::Duration, attempt: u32) -> std::time::Duration {
+        match self.backoff {
+            Backoff::Fixed => delay,
+            Backoff::Exponential => delay.saturating_mul(2u32.saturating_pow(attempt - 1)),
+        }
+    }
+
+    /// Whether a failed attempt may be retried. Timeouts count as exit code 124.
+    fn retries(&self, error: &StepError) -> bool {
+        if self.on_exit_codes.is_empty() {
+            return true;
+        }
+        let code = match error.timed_out {
+            Some(_) => Some(TIMEOUT_EXIT_CODE as i64),
+            None => error.exit_code,
+        };
+        code.is_some_and(|code| self.on_exit_codes.contains(&code))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -46,6 +229,49 @@ struct Stage {
 
     #[serde(default)]
     depends_on: Vec<String>,
+
+    /// Report failed steps of this stage without failing the pipeline
+    #[serde(default, alias = "continue_on_error")]
+    allow_failure: bool,
+
+    /// Condition deciding whether the stage runs
+    #[serde(default, rename = "if")]
+    condition: Option<String>,
+
+    /// Run one copy of the stage per combination of values
+    #[serde(default)]
+    matrix: Option<Matrix>,
+
+    /// Settings applied to the steps of this stage that do not set them itself
+    #[serde(default)]
+    defaults: Defaults,
+
+    /// Artifacts copied into the containers of all steps of this stage
+    #[serde(default)]
+    needs_artifacts: Vec<String>,
+
+    /// Set on the copies made from a stage with a matrix
+    #[serde(skip)]
+    job: Option<matrix::Job>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Matrix {
+    /// Extra combinations, or extra values for matching combinations
+    #[serde(default)]
+    include: Vec<serde_yaml::Mapping>,
+
+    /// Combinations to leave out
+    #[serde(default)]
+    exclude: Vec<serde_yaml::Mapping>,
+
+    /// Maximum number of copies running at once
+    #[serde(default)]
+    max_parallel: Option<usize>,
+
+    /// Lists of values per key, e.g. `node: [18, 20]`
+    #[serde(flatten)]
+    axes: serde_yaml::Mapping,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -64,6 +290,120 @@ struct ForgeConfig {
 
     #[serde(default)]
     secrets: Vec<Secret>,
+
+    /// Makes the project directory available inside step containers
+    #[serde(default)]
+    workspace: Option<WorkspaceConfig>,
+
+    /// Settings applied to every step that does not set them itself
+    #[serde(default)]
+    defaults: Defaults,
+
+    /// Environment variables of every step; a step's own `env` takes precedence
+    #[serde(default)]
+    env: std::collections::HashMap<String, String>,
+
+    /// Values for `${{ vars.NAME }}` references, overridden by `--var NAME=value`
+    #[serde(default)]
+    variables: std::collections::HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+struct Defaults {
+    /// Image of steps without one
+    #[serde(default)]
+    image: Option<String>,
+
+    /// Working directory of steps without one
+    #[serde(default)]
+    working_dir: Option<String>,
+
+    /// Environment variables added to every step; a step's own values take precedence
+    #[serde(default)]
+    env: std::collections::HashMap<String, String>,
+
+    /// Shell running the commands
+    #[serde(default)]
+    shell: Option<String>,
+
+    /// Maximum run time of each step
+    #[serde(default)]
+    timeout: Option<String>,
+
+    /// User the commands run as
+    #[serde(default)]
+    user: Option<String>,
+}
+
+impl Defaults {
+    /// Fill in the settings `step` does not set itself.
+    fn apply_to(&self, step: &mut Step) {
+        if step.image.is_empty() {
+            step.image = self.image.clone().unwrap_or_default();
+        }
+        if step.working_dir.is_empty() {
+            step.working_dir = self.working_dir.clone().unwrap_or_default();
+        }
+        for (name, value) in &self.env {
+            step.env
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+        step.shell = step.shell.take().or_else(|| self.shell.clone());
+        step.timeout = step.timeout.take().or_else(|| self.timeout.clone());
+        step.user = step.user.take().or_else(|| self.user.clone());
+    }
+}
+
+/// Image of steps that neither they nor any `defaults` give one.
+const DEFAULT_IMAGE: &str = "alpine:latest";
+
+/// Shell of steps that neither they nor any `defaults` give one.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Let every step inherit the `defaults` of its stage, then those of the pipeline, and the
+/// artifacts its stage needs.
+fn apply_defaults(config: &mut ForgeConfig) {
+    for stage in &mut config.stages {
+        for step in &mut stage.steps {
+            stage.defaults.apply_to(step);
+            config.defaults.apply_to(step);
+            for name in &stage.needs_artifacts {
+                if !step.needs_artifacts.contains(name) {
+                    step.needs_artifacts.push(name.clone());
+                }
+            }
+        }
+    }
+    for step in &mut config.steps {
+        config.defaults.apply_to(step);
+    }
+}
+
+/// Parse a `timeout` setting. `scope` names the setting in error messages.
+fn parse_timeout(
+    timeout: Option<&str>,
+    scope: &str,
+) -> Result<Option<std::time::Duration>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(text) = timeout else {
+        return Ok(None);
+    };
+    let invalid = |reason: String| {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Configuration validation failed: {scope}: {reason}\n\
+                 Hint: Use a duration like '90s', '10m' or '1h30m'"
+            ),
+        ))
+    };
+    match units::parse_duration(text) {
+        Ok(duration) if duration.is_zero() => Err(invalid(format!(
+            "timeout '{text}' must be greater than zero"
+        ))),
+        Ok(duration) => Ok(Some(duration)),
+        Err(e) => Err(invalid(e)),
+    }
 }
 
 /// Helper function to provide a default value for the configuration version.
@@ -78,6 +418,62 @@ struct CacheConfig {
 
     #[serde(default)]
     enabled: bool,
+
+    /// Keyed cache entries
+    #[serde(default)]
+    entries: Vec<CacheEntry>,
+
+    /// Evict entries unused for longer than this when pruning, e.g. `14d`
+    #[serde(default)]
+    max_age: Option<String>,
+
+    /// Keep the project's cache below this size when pruning, e.g. `5G`
+    #[serde(default)]
+    max_size: Option<String>,
+}
+
+impl CacheConfig {
+    /// Parsed `max_age` and `max_size` limits.
+    fn limits(
+        &self,
+    ) -> Result<(Option<std::time::Duration>, Option<u64>), Box<dyn std::error::Error + Send + Sync>>
+    {
+        let invalid = |e: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Configuration validation failed: cache: {e}\n\
+                     Hint: Use durations like '12h' or '14d' and sizes like '500M' or '5G'"
+                ),
+            )
+        };
+        let max_age = self
+            .max_age
+            .as_deref()
+            .map(units::parse_duration)
+            .transpose()
+            .map_err(invalid)?;
+        let max_size = self
+            .max_size
+            .as_deref()
+            .map(units::parse_size)
+            .transpose()
+            .map_err(invalid)?;
+        Ok((max_age, max_size))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    /// Key template, e.g. `node-${{ hashFiles('package-lock.json') }}`
+    key: String,
+
+    /// Directories inside the container stored under the key
+    paths: Vec<String>,
+
+    /// Key prefixes to restore from when there is no entry for the exact key
+    #[serde(default)]
+    restore_keys: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -87,8 +483,40 @@ struct Secret {
 
     /// Name of the environment variable on the host containing the secret value
     env_var: String,
+
+    /// Skip the secret instead of failing when the host variable is not set
+    #[serde(default)]
+    optional: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct WorkspaceConfig {
+    /// Path of the project inside the container. Defaults to the step's working
+    /// directory, or `/workspace` for steps without one.
+    #[serde(default)]
+    path: String,
+
+    #[serde(default)]
+    mode: WorkspaceMode,
+
+    /// Mount the project read-only (bind mode only)
+    #[serde(default)]
+    read_only: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum WorkspaceMode {
+    /// Bind-mount the project directory, so changes made by steps show up on the host
+    #[default]
+    Bind,
+    /// Copy the project into each container, leaving the host untouched
+    Copy,
 }
 
+/// Container path used for the workspace when neither it nor the step sets one.
+const DEFAULT_WORKSPACE_PATH: &str = "/workspace";
+
 #[derive(Parser)]
 #[command(
     name = "forge",
@@ -122,8 +550,38 @@ enum Commands {
         #[arg(long)]
         no_cache: bool,
 
+        /// Run this stage and the stages it depends on
         #[arg(short, long)]
         stage: Option<String>,
+
+        /// Start at this stage: run it and the stages depending on it
+        #[arg(long, value_name = "STAGE")]
+        from: Option<String>,
+
+        /// Stop after this stage: leave out the stages depending on it
+        #[arg(long, value_name = "STAGE")]
+        until: Option<String>,
+
+        /// Run only the steps matching a name or glob pattern (repeatable)
+        #[arg(long, value_name = "STEP")]
+        only: Vec<String>,
+
+        /// Leave out the steps matching a name or glob pattern (repeatable)
+        #[arg(long, value_name = "STEP")]
+        skip: Vec<String>,
+
+        /// Revision that `changed()` conditions compare the working tree with
+        #[arg(long, default_value = "HEAD")]
+        changed_since: String,
+
+        /// Set a variable for `${{ vars.NAME }}` references (repeatable)
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = interpolate::parse_var)]
+        vars: Vec<(String, String)>,
+
+        /// Run only the steps that did not pass in an earlier run (default: the latest run)
+        /// and the steps depending on them
+        #[arg(long, value_name = "RUN_ID", num_args = 0..=1, default_missing_value = "")]
+        rerun_failed: Option<String>,
     },
 
     Init {
@@ -137,6 +595,95 @@ enum Commands {
     Validate {
         #[arg(short, long, default_value = "forge.yaml")]
         file: String,
+
+        /// Set a variable for `${{ vars.NAME }}` references (repeatable)
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = interpolate::parse_var)]
+        vars: Vec<(String, String)>,
+    },
+
+    /// Inspect and prune the persistent cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+
+    /// List recent runs with their status, duration and git revision
+    History {
+        #[arg(short, long, default_value = "forge.yaml")]
+        file: String,
+
+        /// Number of runs to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Show the stored logs of a run
+    Logs {
+        /// Run id or a unique prefix of it (default: the latest run)
+        run: Option<String>,
+
+        /// Only show the logs of this step
+        #[arg(long)]
+        step: Option<String>,
+
+        #[arg(short, long, default_value = "forge.yaml")]
+        file: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List cache entries with their size and last use
+    List {
+        #[arg(short, long, default_value = "forge.yaml")]
+        file: String,
+
+        /// List the entries of every project
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Show the directories stored in a cache entry
+    Show {
+        key: String,
+
+        #[arg(short, long, default_value = "forge.yaml")]
+        file: String,
+    },
+
+    /// Remove entries that are old or exceed a size budget
+    Prune {
+        #[arg(short, long, default_value = "forge.yaml")]
+        file: String,
+
+        /// Prune the entries of every project against one shared budget
+        #[arg(long)]
+        all: bool,
+
+        /// Remove entries not used within this duration, e.g. 7d (default: cache.max_age)
+        #[arg(long)]
+        older_than: Option<String>,
+
+        /// Remove least recently used entries until the cache fits, e.g. 5G (default: cache.max_size)
+        #[arg(long)]
+        max_size: Option<String>,
+
+        /// Only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Remove cache entries
+    Clear {
+        /// Remove only this entry
+        key: Option<String>,
+
+        #[arg(short, long, default_value = "forge.yaml")]
+        file: String,
+
+        /// Remove the caches of every project
+        #[arg(long)]
+        all: bool,
     },
 }
 
@@ -179,6 +726,44 @@ fn read_forge_config(path: &Path) -> Result<ForgeConfig, Box<dyn std::error::Err
     })?;
     Ok(config)
 }
+
+/// Identifier of a new run, e.g. `20240501-142233-3f9a1c`; ids sort by start time.
+fn new_run_id() -> String {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!(
+        "{}-{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S"),
+        &suffix[..6]
+    )
+}
+
+/// Expand `${{ ... }}` references in the configuration, see the `interpolate` module.
+fn interpolate_config(
+    config: &mut ForgeConfig,
+    vars: &[(String, String)],
+    run_id: &str,
+    project_dir: &Path,
+    git: &git::GitInfo,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let builtins = |name: &str| match name {
+        "forge.run_id" => Some(run_id.to_string()),
+        "forge.project_dir" => Some(project_dir.display().to_string()),
+        "git.sha" => git.sha(),
+        "git.branch" => git.branch(),
+        _ => None,
+    };
+    interpolate::apply(config, vars, &builtins)
+}
+
+/// Absolute path of the project directory, i.e. the directory holding the config file.
+fn project_dir_of(config_path: &Path) -> std::io::Result<PathBuf> {
+    config_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .canonicalize()
+}
+
 async fn pull_image(
     docker: &Docker,
     image: &str,
@@ -230,55 +815,456 @@ async fn pull_image(
     ));
     Ok(())
 }
-async fn run_command_in_container(
-    docker: &Docker,
-    step: &Step,
-    verbose: bool,
-    cache_config: &CacheConfig,
-    temp_dir: &std::path::Path,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let image = if step.image.is_empty() {
-        "alpine:latest"
-    } else {
-        &step.image
-    };
 
-    // Pull the image if needed
-    pull_image(docker, image).await?;
+/// Exit code of `forge` when a step is stopped for exceeding its timeout, as for `timeout(1)`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
 
-    // Create a unique container name
-    let container_name = format!("forge-{}", uuid::Uuid::new_v4());
+/// Time a timed out container gets to stop after SIGTERM before it is killed.
+const STOP_GRACE_PERIOD_SECS: i64 = 10;
 
-    // Prepare environment variables
-    let env: Vec<String> = step.env.iter().map(|(k, v)| format!("{k}={v}")).collect();
+/// A step whose container did not exit successfully.
+#[derive(Debug)]
+struct StepError {
+    step: String,
+    /// Exit code of the container, if Docker reported one
+    exit_code: Option<i64>,
+    /// Set when the step was stopped for exceeding this timeout
+    timed_out: Option<std::time::Duration>,
+    /// Number of times the step was run
+    attempts: u32,
+    command: String,
+    image: String,
+}
 
-    // Create container
-    let step_name = if step.name.is_empty() {
-        "unnamed step"
-    } else {
-        &step.name
-    };
-    println!("{}", format!("Running step: {step_name}").yellow().bold());
-    if verbose {
-        println!("  Command: {command}", command = step.command);
-        println!("  Image: {image}");
-        if !step.working_dir.is_empty() {
-            println!("  Working directory: {dir}", dir = step.working_dir);
+impl StepError {
+    /// Exit code for the `forge` process itself: the container's code when it fits, else 1.
+    fn process_exit_code(&self) -> i32 {
+        if self.timed_out.is_some() {
+            return TIMEOUT_EXIT_CODE;
         }
-        if !step.env.is_empty() {
-            println!("  Environment variables:");
-            for (k, v) in &step.env {
-                println!("    {k}={v}");
-            }
+        match self.exit_code {
+            Some(code @ 1..=255) => code as i32,
+            _ => 1,
         }
     }
+}
 
-    let options = Some(CreateContainerOptions {
-        name: container_name.clone(),
-        ..Default::default()
-    });
+impl std::fmt::Display for StepError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.timed_out, self.exit_code) {
+            (Some(timeout), _) => write!(
+                f,
+                "Step '{}' timed out after {}",
+                self.step,
+                units::format_duration(timeout)
+            )?,
+            (None, Some(code)) => write!(f, "Step '{}' failed with exit code {code}", self.step)?,
+            (None, None) => write!(
+                f,
+                "Step '{}' failed without reporting an exit code",
+                self.step
+            )?,
+        }
+        if self.attempts > 1 {
+            write!(f, " ({} attempts)", self.attempts)?;
+        }
+        write!(
+            f,
+            "\nCommand: {}\n\
+             Image: {}\n",
+            self.command, self.image
+        )?;
+        if self.timed_out.is_some() {
+            write!(
+                f,
+                "Hint: Check the output above for where the step hung, \n\
+                 or raise 'timeout' on the step or in 'defaults'"
+            )
+        } else {
+            write!(
+                f,
+                "Hint: Check the command output above for error details. \n\
+                 You can run with --verbose for more detailed logging"
+            )
+        }
+    }
+}
 
-    // Setup volume mounts for caching
+impl std::error::Error for StepError {}
+
+/// Several failures reported as one error.
+///
+/// The first failure is exposed as the source, so its exit code becomes the exit code of
+/// `forge run`.
+#[derive(Debug)]
+struct FailureSummary {
+    message: String,
+    errors: Vec<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl std::fmt::Display for FailureSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for FailureSummary {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.errors
+            .first()
+            .map(|e| e.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// How a step ended, as shown in the run summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StepStatus {
+    Passed,
+    Failed,
+    /// Failed, but the step or its stage has `allow_failure` set
+    FailedAllowed,
+    /// Not run, e.g. because a dependency failed
+    Skipped,
+    /// Not run again, the result of an earlier run is used, see `run --rerun-failed`
+    Reused,
+}
+
+impl std::fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StepStatus::Passed => "passed",
+            StepStatus::Failed => "failed",
+            StepStatus::FailedAllowed => "failed (allowed)",
+            StepStatus::Skipped => "skipped",
+            StepStatus::Reused => "reused",
+        })
+    }
+}
+
+/// The result of a step that was run.
+#[derive(Debug, Clone)]
+struct StepResult {
+    stage: String,
+    /// Position of the step in its stage
+    index: usize,
+    status: StepStatus,
+    /// Exit code of the step's (last) container, if Docker reported one
+    exit_code: Option<i64>,
+    duration: std::time::Duration,
+}
+
+/// The steps of a run in configuration order, for its `run.json`. Steps without a result
+/// were skipped.
+fn step_records(
+    stages: &[Stage],
+    results: &[StepResult],
+    outputs: &StepOutputs,
+    secrets: &Secrets,
+) -> Vec<runs::StepRecord> {
+    let mut records = Vec::new();
+    for stage in stages {
+        for (index, step) in stage.steps.iter().enumerate() {
+            let result = results
+                .iter()
+                .find(|r| r.stage == stage.name && r.index == index);
+            let log = runs::log_file(&stage.name, index, step.display_name());
+            records.push(runs::StepRecord {
+                stage: stage.name.clone(),
+                name: step.display_name().to_string(),
+                status: result.map_or(StepStatus::Skipped, |r| r.status),
+                exit_code: result.and_then(|r| r.exit_code),
+                duration_secs: result.map(|r| r.duration.as_secs_f64()),
+                log: result.map(|_| log.to_string_lossy().replace('\\', "/")),
+                outputs: outputs
+                    .get(&(stage.name.clone(), index))
+                    .filter(|_| result.is_some())
+                    .map(|output| {
+                        output
+                            .values
+                            .iter()
+                            .map(|(key, value)| (key.clone(), secrets.mask(value)))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            });
+        }
+    }
+    records
+}
+
+/// Print the status of every step in configuration order. Steps without a result were
+/// skipped.
+fn print_summary(stages: &[Stage], results: &[StepResult]) {
+    println!("{}", "Summary:".cyan().bold());
+    let statuses = [
+        StepStatus::Passed,
+        StepStatus::Failed,
+        StepStatus::FailedAllowed,
+        StepStatus::Skipped,
+        StepStatus::Reused,
+    ];
+    let mut counts = [0; 5];
+    for stage in stages {
+        for (index, step) in stage.steps.iter().enumerate() {
+            let result = results
+                .iter()
+                .find(|r| r.stage == stage.name && r.index == index);
+            let status = result.map_or(StepStatus::Skipped, |r| r.status);
+            counts[status as usize] += 1;
+
+            let name = if stages.len() > 1 {
+                format!("{} / {}", stage.name, step.display_name())
+            } else {
+                step.display_name().to_string()
+            };
+            let mut details = String::new();
+            if let Some(result) = result.filter(|r| r.status != StepStatus::Reused) {
+                if let (StepStatus::Failed | StepStatus::FailedAllowed, Some(code)) =
+                    (result.status, result.exit_code)
+                {
+                    details.push_str(&format!(", exit code {code}"));
+                }
+                details = format!(" ({}{details})", units::format_duration(result.duration));
+            }
+            let label = format!("{:<16}", status.to_string());
+            let label = match status {
+                StepStatus::Passed => label.green(),
+                StepStatus::Failed => label.red(),
+                StepStatus::FailedAllowed => label.yellow(),
+                StepStatus::Skipped => label.dimmed(),
+                StepStatus::Reused => label.green().dimmed(),
+            };
+            println!("  {label} {name}{details}");
+        }
+    }
+    let totals: Vec<String> = statuses
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(status, count)| format!("{count} {status}"))
+        .collect();
+    println!("{}", totals.join(", "));
+}
+
+/// Exit code for a failed command: that of the first failed step, or 1 for other errors.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if let Some(step_error) = e.downcast_ref::<StepError>() {
+            return step_error.process_exit_code();
+        }
+        current = e.source();
+    }
+    1
+}
+
+/// Everything shared by the steps of a single pipeline run.
+#[derive(Clone, Copy)]
+struct RunContext<'a> {
+    docker: &'a Docker,
+    verbose: bool,
+    /// Cache entries used by every step; empty when caching is disabled
+    cache_entries: &'a [cache::ResolvedEntry],
+    /// Host directory mounted into every step at `/forge-shared`
+    temp_dir: &'a Path,
+    /// Directory keeping the logs and artifacts of the run, see the `runs` module
+    run_dir: &'a Path,
+    /// Persistent cache directory of the project, kept between runs
+    cache_dir: &'a Path,
+    secrets: &'a Secrets,
+    workspace: Option<&'a WorkspaceConfig>,
+    /// Directory containing the configuration file
+    project_dir: &'a Path,
+    /// Results of the steps run so far
+    results: &'a std::sync::Mutex<Vec<StepResult>>,
+    /// Outputs of the steps run so far, by stage and step position
+    outputs: &'a std::sync::Mutex<StepOutputs>,
+    /// Git state used by `if:` conditions
+    git: &'a git::GitInfo,
+    /// Earlier run whose passed steps are reused, for `--rerun-failed`
+    rerun: Option<&'a rerun::Rerun>,
+}
+
+/// Outputs written by each step to its `FORGE_OUTPUT` file, keyed by stage and position of
+/// the step in the stage.
+type StepOutputs = std::collections::HashMap<(String, usize), StepOutput>;
+
+/// Outputs of one step.
+#[derive(Debug, Clone, Default)]
+struct StepOutput {
+    /// Name of the step, for references from other stages
+    step: String,
+    values: std::collections::HashMap<String, String>,
+}
+
+/// Value of the output `key` of step `name`, as referenced from a step of `stage`.
+///
+/// A step of the same stage comes first, so that the copies of a stage with a matrix each
+/// see their own outputs. Otherwise the step is looked up in the other stages, where
+/// `validate_settings` made sure its name is unique.
+fn lookup_output<'a>(
+    outputs: &'a StepOutputs,
+    stage: &Stage,
+    name: &str,
+    key: &str,
+) -> Option<&'a String> {
+    let output = match stage.steps.iter().position(|s| s.name == name) {
+        Some(index) => outputs.get(&(stage.name.clone(), index)),
+        None => outputs.values().find(|output| output.step == name),
+    };
+    output?.values.get(key)
+}
+
+/// Replace the `${{ steps.NAME.outputs.KEY }}` references in the step's fields with the
+/// outputs of the steps that ran before it.
+fn resolve_outputs(
+    ctx: &RunContext<'_>,
+    stage: &Stage,
+    step: &Step,
+) -> Result<Step, Box<dyn std::error::Error + Send + Sync>> {
+    let outputs = ctx.outputs.lock().unwrap();
+    let substitute = |text: &str| {
+        outputs::substitute(text, |name, key| {
+            lookup_output(&outputs, stage, name, key).cloned()
+        })
+        .map_err(|reference| {
+            Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "Step '{}' references {reference}, which has no value\n\
+                         Hint: Make sure the step it names ran before this one, e.g. with \
+                         'depends_on', and writes the key to $FORGE_OUTPUT: \
+                         echo \"KEY=value\" >> \"$FORGE_OUTPUT\"",
+                    step.display_name()
+                ),
+            ))
+        })
+    };
+
+    let mut resolved = step.clone();
+    resolved.image = substitute(&step.image)?;
+    resolved.working_dir = substitute(&step.working_dir)?;
+    resolved.command = substitute(&step.command)?;
+    for value in resolved.env.values_mut() {
+        *value = substitute(value)?;
+    }
+    Ok(resolved)
+}
+
+async fn run_command_in_container(
+    ctx: &RunContext<'_>,
+    stage: &Stage,
+    index: usize,
+    step: &Step,
+    log_prefix: Option<&str>,
+    log_path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let RunContext {
+        docker,
+        verbose,
+        cache_entries,
+        temp_dir,
+        run_dir,
+        cache_dir,
+        secrets,
+        workspace,
+        project_dir,
+        results: _,
+        outputs: step_outputs,
+        git: _,
+        rerun: _,
+    } = *ctx;
+
+    let image = if step.image.is_empty() {
+        DEFAULT_IMAGE
+    } else {
+        &step.image
+    };
+    let artifacts_dir = run_dir.join("artifacts");
+
+    // Pull the image if needed
+    pull_image(docker, image).await?;
+
+    let step_name = step.display_name();
+    let timeout = parse_timeout(step.timeout.as_deref(), &format!("step '{step_name}'"))?;
+    let mut cmd: Vec<String> = step
+        .shell
+        .as_deref()
+        .unwrap_or(DEFAULT_SHELL)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    cmd.extend(["-c".to_string(), step.command.clone()]);
+
+    // Create a unique container name
+    let container_name = format!("forge-{}", uuid::Uuid::new_v4());
+
+    // The step writes its outputs to a file in the shared directory
+    let output_file = temp_dir.join("outputs").join(&container_name);
+    let output_path = format!("/forge-shared/outputs/{container_name}");
+    std::fs::create_dir_all(temp_dir.join("outputs"))
+        .and_then(|_| File::create(&output_file))
+        .map_err(|e| {
+            Box::new(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "Failed to create output file '{}' for step '{step_name}': {e}\n\
+                     Hint: Check permissions and disk space in your temp directory",
+                    output_file.display()
+                ),
+            ))
+        })?;
+
+    // Prepare environment variables, secrets take precedence over the step's own values
+    let mut env_vars: std::collections::HashMap<&str, &str> = step
+        .env
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    env_vars.insert(outputs::OUTPUT_ENV, &output_path);
+    env_vars.extend(secrets.env());
+    let env: Vec<String> = env_vars.iter().map(|(k, v)| format!("{k}={v}")).collect();
+
+    // Create container
+    println!("{}", format!("Running step: {step_name}").yellow().bold());
+    let mut log = runs::StepLog::open(log_path);
+    log.line(&format!("==> Step: {step_name}"));
+    log.line(&format!("==> Image: {image}"));
+    log.line(&format!("==> Command: {}", secrets.mask(&step.command)));
+    if verbose {
+        println!("  Command: {}", secrets.mask(&step.command));
+        println!("  Image: {image}");
+        if !step.working_dir.is_empty() {
+            println!("  Working directory: {dir}", dir = step.working_dir);
+        }
+        if !step.env.is_empty() {
+            println!("  Environment variables:");
+            for (k, v) in &step.env {
+                println!("    {k}={}", secrets.mask(v));
+            }
+        }
+        let secret_names: Vec<&str> = secrets.names().collect();
+        if !secret_names.is_empty() {
+            println!("  Secrets: {}", secret_names.join(", "));
+        }
+        if let Some(timeout) = timeout {
+            println!("  Timeout: {}", units::format_duration(timeout));
+        }
+        if let Some(shell) = &step.shell {
+            println!("  Shell: {shell}");
+        }
+        if let Some(user) = &step.user {
+            println!("  User: {user}");
+        }
+    }
+
+    let options = Some(CreateContainerOptions {
+        name: container_name.clone(),
+        ..Default::default()
+    });
+
+    // Setup volume mounts for caching
     let mut mounts = vec![];
 
     // Add bind mount for shared data between steps
@@ -290,6 +1276,31 @@ async fn run_command_in_container(
     };
     mounts.push(shared_mount);
 
+    // Make the project available at the workspace path
+    let workspace_path = workspace.map(|ws| {
+        if !ws.path.is_empty() {
+            ws.path.clone()
+        } else if !step.working_dir.is_empty() {
+            step.working_dir.clone()
+        } else {
+            DEFAULT_WORKSPACE_PATH.to_string()
+        }
+    });
+    if let (Some(ws), Some(path)) = (workspace, &workspace_path) {
+        if ws.mode == WorkspaceMode::Bind {
+            mounts.push(Mount {
+                target: Some(path.clone()),
+                source: Some(project_dir.to_string_lossy().to_string()),
+                typ: Some(MountTypeEnum::BIND),
+                read_only: Some(ws.read_only),
+                ..Default::default()
+            });
+        }
+        if verbose {
+            println!("  Workspace: {} -> {path}", project_dir.display());
+        }
+    }
+
     // Setup host config with mounts
     let host_config = HostConfig {
         auto_remove: Some(false), // Change to false to prevent automatic removal
@@ -297,51 +1308,13 @@ async fn run_command_in_container(
         ..Default::default()
     };
 
-    // If caching is enabled, add cache directories to the command
-    let mut command = step.command.clone();
-    if cache_config.enabled && !cache_config.directories.is_empty() {
-        // Create a script for cache setup
-        let mut cache_setup = String::new();
-        for dir in &cache_config.directories {
-            // Create the directory in the shared volume if it doesn't exist
-            cache_setup.push_str(&format!("mkdir -p /forge-shared{dir}\n"));
-            // Create the target directory if it doesn't exist
-            cache_setup.push_str(&format!("mkdir -p {dir}\n"));
-            // Copy from shared volume to the target directory if it exists
-            cache_setup.push_str(&format!("if [ -d /forge-shared{dir} ] && [ \"$(ls -A /forge-shared{dir})\" ]; then cp -r /forge-shared{dir}/* {dir}/ 2>/dev/null || true; fi\n"));
-        }
-
-        // Create a script for cache teardown
-        let mut cache_teardown = String::new();
-        for dir in &cache_config.directories {
-            // Create the directory in the shared volume if it doesn't exist
-            cache_teardown.push_str(&format!("mkdir -p /forge-shared{dir}\n"));
-            // Copy from the target directory to the shared volume if it exists
-            cache_teardown.push_str(&format!("if [ -d {dir} ] && [ \"$(ls -A {dir})\" ]; then cp -r {dir}/* /forge-shared{dir}/ 2>/dev/null || true; fi\n"));
-        }
-
-        // Create a combined script
-        let script = format!(
-            "#!/bin/sh\n\n# Cache setup\n{cache_setup}\n# Main command\n{command}\n\n# Cache teardown\n{cache_teardown}\n\n# Exit with the status of the main command\nexit $?",
-        );
-
-        // Use the script as the command
-        command = script;
-
-        if verbose {
-            println!(
-                "  Cache enabled for directories: {:?}",
-                cache_config.directories
-            );
-        }
-    }
-
     let config = Config {
         image: Some(image.to_string()),
-        cmd: Some(vec!["/bin/sh".to_string(), "-c".to_string(), command]),
+        cmd: Some(cmd),
         env: Some(env),
+        user: step.user.clone(),
         working_dir: if step.working_dir.is_empty() {
-            None
+            workspace_path.clone()
         } else {
             Some(step.working_dir.clone())
         },
@@ -365,21 +1338,89 @@ async fn run_command_in_container(
             )))
         })?;
 
-    // Start container
-    docker
-        .start_container::<String>(&container.id, None)
-        .await
-        .map_err(|e| {
-            Box::new(std::io::Error::other(format!(
-                "Failed to start Docker container '{}' for step '{}': {}\n\
-                     Possible causes:\n\
-                     • Docker daemon stopped responding\n\
-                     • Container configuration is invalid\n\
-                     • Insufficient system resources\n\
-                     Hint: Check Docker daemon status with 'docker info'",
-                container.id, step_name, e
-            )))
-        })?;
+    // Prepare and start the container; it is removed again if any of this fails
+    let setup: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        // Copy the project in before the container starts
+        let copy_workspace = workspace.is_some_and(|ws| ws.mode == WorkspaceMode::Copy);
+        if let Some(path) = workspace_path.as_ref().filter(|_| copy_workspace) {
+            let source = project_dir.to_path_buf();
+            let target = path.clone();
+            let copied =
+                match tokio::task::spawn_blocking(move || archive::pack_directory(&source, &target))
+                    .await
+                {
+                    Ok(Ok(tar)) => archive::upload(docker, &container.id, tar).await,
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(e) => Err(e.into()),
+                };
+            if let Err(e) = copied {
+                return Err(std::io::Error::other(format!(
+                    "Failed to copy workspace '{}' into container for step '{}': {}\n\
+                     Hint: Check that the project directory is readable, or use 'mode: bind'",
+                    project_dir.display(),
+                    step_name,
+                    e
+                ))
+                .into());
+            }
+        }
+
+        // Restore cached directories before the step runs
+        if !cache_entries.is_empty() {
+            cache::restore(docker, &container.id, cache_dir, cache_entries, verbose).await;
+        }
+
+        // Copy in the artifacts of earlier steps
+        for name in &step.needs_artifacts {
+            let dir = artifacts::artifact_dir(&artifacts_dir, name);
+            let copied = if dir.is_dir() {
+                match tokio::task::spawn_blocking(move || artifacts::pack(&dir)).await {
+                    Ok(Ok(tar)) => archive::upload(docker, &container.id, tar).await,
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(e) => Err(e.into()),
+                }
+            } else {
+                Err("it was not collected in this run".into())
+            };
+            if let Err(e) = copied {
+                return Err(std::io::Error::other(format!(
+                    "Failed to copy artifact '{name}' into container for step '{step_name}': {e}\n\
+                     Hint: The step producing it must run first (use 'depends_on' or a later stage) \
+                     and its 'artifacts' paths must exist when it finishes"
+                ))
+                .into());
+            }
+            if verbose {
+                println!("  Restored artifact '{name}'");
+            }
+        }
+
+        // Start container
+        docker
+            .start_container::<String>(&container.id, None)
+            .await
+            .map_err(|e| {
+                Box::new(std::io::Error::other(format!(
+                    "Failed to start Docker container '{}' for step '{}': {}\n\
+                         Possible causes:\n\
+                         • Docker daemon stopped responding\n\
+                         • Container configuration is invalid\n\
+                         • Insufficient system resources\n\
+                         Hint: Check Docker daemon status with 'docker info'",
+                    container.id, step_name, e
+                )))
+            })?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = setup {
+        let _ = docker.remove_container(&container.id, None).await;
+        return Err(e);
+    }
+
+    // Logs and the exit status are awaited against the step's deadline, if any
+    let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+    let mut timed_out = false;
 
     // Wait for container to finish first
     let mut wait_stream = docker.wait_container::<String>(&container.id, None);
@@ -395,81 +1436,965 @@ async fn run_command_in_container(
 
     let mut log_stream = docker.logs::<String>(&container.id, Some(log_options));
 
-    while let Some(result) = log_stream.next().await {
+    // Steps of a parallel stage share the terminal, so tag every line with its step
+    let prefix = log_prefix
+        .map(|p| format!("{} ", format!("[{p}]").blue()))
+        .unwrap_or_default();
+
+    // Output is re-assembled into lines so secrets split across chunks are still masked
+    let mut stdout_lines = MaskedLines::new(secrets);
+    let mut stderr_lines = MaskedLines::new(secrets);
+
+    loop {
+        let next = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, log_stream.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    timed_out = true;
+                    break;
+                }
+            },
+            None => log_stream.next().await,
+        };
+        let Some(result) = next else {
+            break;
+        };
         match result {
             Ok(output) => match output {
                 bollard::container::LogOutput::StdOut { message } => {
-                    println!("{}", String::from_utf8_lossy(&message));
+                    for line in stdout_lines.push(&message) {
+                        println!("{prefix}{line}");
+                        log.line(&line);
+                    }
                 }
                 bollard::container::LogOutput::StdErr { message } => {
-                    eprintln!("{}", String::from_utf8_lossy(&message).red());
+                    for line in stderr_lines.push(&message) {
+                        eprintln!("{prefix}{}", line.red());
+                        log.stderr(&line);
+                    }
                 }
                 _ => {}
             },
             Err(e) => {
-                eprintln!("Error streaming logs: {e}");
+                eprintln!("Error streaming logs: {}", secrets.mask(&e.to_string()));
                 break;
             }
         }
     }
+    if let Some(line) = stdout_lines.finish() {
+        println!("{prefix}{line}");
+        log.line(&line);
+    }
+    if let Some(line) = stderr_lines.finish() {
+        eprintln!("{prefix}{}", line.red());
+        log.stderr(&line);
+    }
 
     // Get the wait result
-    let wait_result = wait_future.await;
-
-    // Process the wait result
-    let exit_status = match wait_result {
-        Some(Ok(exit)) => {
-            if exit.status_code == 0 {
-                println!(
-                    "{}",
-                    format!("Step completed successfully: {step_name}")
-                        .green()
-                        .bold()
-                );
-                true
-            } else {
-                let error_msg = format!(
-                    "Step failed with exit code {}: {}",
-                    exit.status_code, step_name
-                );
-                println!("{}", error_msg.red().bold());
-                false
+    let wait_result = match deadline {
+        _ if timed_out => None,
+        Some(deadline) => match tokio::time::timeout_at(deadline, wait_future).await {
+            Ok(result) => result,
+            Err(_) => {
+                timed_out = true;
+                None
             }
+        },
+        None => wait_future.await,
+    };
+
+    if let (true, Some(timeout)) = (timed_out, timeout) {
+        println!(
+            "{}",
+            format!(
+                "Step timed out after {}: {step_name}",
+                units::format_duration(timeout)
+            )
+            .red()
+            .bold()
+        );
+        let options = bollard::container::StopContainerOptions {
+            t: STOP_GRACE_PERIOD_SECS,
+        };
+        if let Err(e) = docker.stop_container(&container.id, Some(options)).await {
+            eprintln!("Failed to stop container: {e}");
         }
+    }
+
+    // Process the wait result. Bollard reports non-zero exits as an error carrying the code.
+    let exit_code = match wait_result {
+        Some(Ok(exit)) => Some(exit.status_code),
+        Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. })) => Some(code),
         Some(Err(e)) => {
             let error_msg = format!("Error waiting for container: {e}");
             println!("{}", error_msg.red().bold());
-            false
+            None
         }
+        None if timed_out => None,
         None => {
             let error_msg = "Container exited without providing a status code";
             println!("{}", error_msg.red().bold());
-            false
+            None
         }
     };
+    match (exit_code, timeout.filter(|_| timed_out)) {
+        (_, Some(timeout)) => log.line(&format!(
+            "==> Timed out after {}",
+            units::format_duration(timeout)
+        )),
+        (Some(code), None) => log.line(&format!("==> Exit code: {code}")),
+        (None, None) => log.line("==> No exit code"),
+    }
+    match exit_code {
+        Some(0) => println!(
+            "{}",
+            format!("Step completed successfully: {step_name}")
+                .green()
+                .bold()
+        ),
+        Some(code) => println!(
+            "{}",
+            format!("Step failed with exit code {code}: {step_name}")
+                .red()
+                .bold()
+        ),
+        None => {}
+    }
+
+    // Outputs are kept even if the step failed, for `failure()` steps reporting on it
+    if let Ok(contents) = std::fs::read_to_string(&output_file) {
+        let values = outputs::parse(&contents);
+        if verbose && !values.is_empty() {
+            println!("  Outputs:");
+            let mut keys: Vec<&String> = values.keys().collect();
+            keys.sort();
+            for key in keys {
+                println!("    {key}={}", secrets.mask(&values[key]));
+            }
+        }
+        let output = StepOutput {
+            step: step_name.to_string(),
+            values,
+        };
+        step_outputs
+            .lock()
+            .unwrap()
+            .insert((stage.name.clone(), index), output);
+    }
+    let _ = std::fs::remove_file(&output_file);
 
-    // Clean up the container manually
-    match docker.remove_container(&container.id, None).await {
+    // Collect artifacts, replacing those of an earlier attempt
+    if let (Some(artifact), Some(name)) = (&step.artifacts, step.artifact_name())
+        && artifact.when.applies(exit_code == Some(0) && !timed_out)
+    {
+        let working_dir = if step.working_dir.is_empty() {
+            workspace_path.as_deref().unwrap_or_default()
+        } else {
+            &step.working_dir
+        };
+        let paths: Vec<String> = artifact
+            .paths
+            .iter()
+            .map(|path| artifacts::container_path(path, working_dir))
+            .collect();
+        let dir = artifacts::artifact_dir(&artifacts_dir, name);
+        let _ = std::fs::remove_dir_all(&dir);
+        match artifacts::collect(docker, &container.id, &paths, &dir).await {
+            Ok(0) => eprintln!(
+                "{}",
+                format!("Warning: No files found for artifact '{name}'").yellow()
+            ),
+            Ok(count) => println!("Saved artifact '{name}' ({count} files)"),
+            Err(e) => eprintln!(
+                "{}",
+                format!("Warning: Failed to save artifact '{name}': {e}").yellow()
+            ),
+        }
+    }
+
+    // Only a successful step updates the cache, a half-finished install would poison it
+    if !cache_entries.is_empty() && exit_code == Some(0) {
+        cache::save(docker, &container.id, cache_dir, cache_entries, verbose).await;
+    }
+
+    // Clean up the container manually; force in case stopping a timed out step failed
+    let remove_options = bollard::container::RemoveContainerOptions {
+        force: timed_out,
+        ..Default::default()
+    };
+    match docker
+        .remove_container(&container.id, Some(remove_options))
+        .await
+    {
         Ok(_) => println!("Container removed: {}", container.id),
         Err(e) => eprintln!("Failed to remove container: {e}"),
     }
-    if exit_status {
+    if exit_code == Some(0) && !timed_out {
         Ok(())
     } else {
-        Err(Box::new(std::io::Error::other(format!(
-            "Step '{}' failed with exit code {}\n\
-                 Command: {}\n\
-                 Image: {}\n\
-                 Hint: Check the command output above for error details. \n\
-                 You can run with --verbose for more detailed logging",
-            step_name,
-            "non-zero", // We'll need to capture the actual exit code
-            step.command,
-            image
-        ))))
+        Err(Box::new(StepError {
+            step: step_name.to_string(),
+            exit_code,
+            timed_out: timeout.filter(|_| timed_out),
+            attempts: 1,
+            command: secrets.mask(&step.command),
+            image: image.to_string(),
+        }))
     }
 }
 
+/// Run the step at position `index` of a stage, re-running it in a fresh container as
+/// allowed by its `retry` policy.
+async fn run_step(
+    ctx: &RunContext<'_>,
+    stage: &Stage,
+    index: usize,
+    step: &Step,
+    log_prefix: Option<&str>,
+    log_path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let step = &resolve_outputs(ctx, stage, step)?;
+    let Some(retry) = &step.retry else {
+        return run_command_in_container(ctx, stage, index, step, log_prefix, log_path).await;
+    };
+    let step_name = step.display_name();
+    let delay = retry.delay(&format!("step '{step_name}'"))?;
+    let attempts = retry.attempts;
+
+    let mut attempt = 1;
+    loop {
+        let started = std::time::Instant::now();
+        let mut error =
+            match run_command_in_container(ctx, stage, index, step, log_prefix, log_path).await {
+                Ok(()) => {
+                    if attempt > 1 {
+                        println!(
+                            "{}",
+                            format!("Step succeeded on attempt {attempt}/{attempts}: {step_name}")
+                                .green()
+                                .bold()
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+        // Only failures of the step itself are retried, not Docker or configuration errors
+        let Some(step_error) = error.downcast_mut::<StepError>() else {
+            return Err(error);
+        };
+        step_error.attempts = attempt;
+
+        let outcome = match (step_error.timed_out, step_error.exit_code) {
+            (Some(_), _) => "timed out".to_string(),
+            (None, Some(code)) => format!("exit code {code}"),
+            (None, None) => "no exit code".to_string(),
+        };
+        println!(
+            "{}",
+            format!(
+                "Attempt {attempt}/{attempts} of {step_name} failed ({outcome}) after {}",
+                units::format_duration(started.elapsed())
+            )
+            .yellow()
+        );
+
+        if attempt >= attempts {
+            return Err(error);
+        }
+        if !retry.retries(step_error) {
+            println!(
+                "{}",
+                format!("Not retrying {step_name}: {outcome} is not listed in on_exit_codes")
+                    .yellow()
+            );
+            return Err(error);
+        }
+        let pause = retry.pause_after(delay, attempt);
+        if pause.is_zero() {
+            println!("{}", format!("Retrying {step_name}").yellow());
+        } else {
+            println!(
+                "{}",
+                format!("Retrying {step_name} in {}", units::format_duration(pause)).yellow()
+            );
+            tokio::time::sleep(pause).await;
+        }
+        attempt += 1;
+    }
+}
+
+/// One semaphore per matrix with a `max_parallel` limit, keyed by the name of the step or
+/// stage the copies were made from.
+fn matrix_limits<'a>(
+    jobs: impl Iterator<Item = Option<&'a matrix::Job>>,
+) -> std::collections::HashMap<String, tokio::sync::Semaphore> {
+    jobs.flatten()
+        .filter_map(|job| {
+            let limit = tokio::sync::Semaphore::new(job.max_parallel?.max(1));
+            Some((job.group.clone(), limit))
+        })
+        .collect()
+}
+
+/// Dependencies of a node that did not succeed, and whether anything upstream of it failed.
+///
+/// `failed_upstream` remembers the latter for every node, so that a failure is still seen
+/// past nodes that ran because of a `failure()` or `always()` condition.
+fn upstream_state<'g>(
+    graph: &'g DependencyGraph,
+    node: usize,
+    upstream: &[Status],
+    failed_upstream: &mut [bool],
+) -> (Vec<&'g str>, bool) {
+    let dependencies = graph.dependencies(node);
+    let blocked_by = dependencies
+        .iter()
+        .zip(upstream)
+        .filter(|(_, status)| **status != Status::Succeeded)
+        .map(|(&dep, _)| graph.name(dep))
+        .collect();
+    let failure = dependencies
+        .iter()
+        .zip(upstream)
+        .any(|(&dep, status)| *status == Status::Failed || failed_upstream[dep]);
+    failed_upstream[node] = failure;
+    (blocked_by, failure)
+}
+
+/// Whether a step or stage runs, given its `if:` condition and whether everything it
+/// depends on succeeded or something upstream failed. Without a condition it runs on
+/// success only. `env` takes precedence over host variables in `env.NAME`.
+fn should_run(
+    git: &git::GitInfo,
+    condition: Option<&str>,
+    success: bool,
+    failure: bool,
+    env: &std::collections::HashMap<String, String>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(text) = condition else {
+        return Ok(success);
+    };
+    let condition = parse_condition(text, "condition")?;
+    let env = |name: &str| env.get(name).cloned().or_else(|| env::var(name).ok());
+    let branch = || git.branch();
+    let changed = |patterns: &[String]| git.changed(patterns);
+    Ok(condition.evaluate(&expr::Context {
+        success,
+        failure,
+        env: &env,
+        branch: &branch,
+        changed: &changed,
+    }))
+}
+
+/// Parse an `if:` condition. `scope` names the stage or step in error messages.
+fn parse_condition(
+    text: &str,
+    scope: &str,
+) -> Result<expr::Condition, Box<dyn std::error::Error + Send + Sync>> {
+    expr::Condition::parse(text).map_err(|e| {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Configuration validation failed: {scope}: invalid if condition '{text}': {e}\n\
+                 Hint: Conditions look like \"failure()\" or \"success() && git.branch == 'main'\""
+            ),
+        )) as Box<dyn std::error::Error + Send + Sync>
+    })
+}
+
+/// Explanation printed for a skipped step or stage.
+fn skip_reason(condition: Option<&str>, blocked_by: &[&str]) -> String {
+    match condition {
+        Some(condition) => format!("condition '{condition}' is false"),
+        None if !blocked_by.is_empty() => {
+            format!("dependency {} did not succeed", blocked_by.join(", "))
+        }
+        None => "an earlier step failed".to_string(),
+    }
+}
+
+/// Run a step of a stage and record its result.
+async fn run_recorded(
+    ctx: &RunContext<'_>,
+    stage: &Stage,
+    index: usize,
+    log_prefix: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let step = &stage.steps[index];
+    if let Some(rerun) = ctx.rerun
+        && let Some(previous) = rerun.reused(&stage.name, index)
+    {
+        println!(
+            "{}",
+            format!(
+                "Reusing step {} from run {}",
+                step.display_name(),
+                rerun.run.record.id
+            )
+            .green()
+        );
+        ctx.results
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(StepResult {
+                stage: stage.name.clone(),
+                index,
+                status: StepStatus::Reused,
+                exit_code: previous.exit_code,
+                duration: std::time::Duration::from_secs_f64(previous.duration_secs.unwrap_or(0.0)),
+            });
+        return Ok(());
+    }
+    let started = std::time::Instant::now();
+    let log_path = ctx
+        .run_dir
+        .join(runs::log_file(&stage.name, index, step.display_name()));
+    let result = run_step(ctx, stage, index, step, log_prefix, &log_path).await;
+    if let Err(e) = &result {
+        runs::StepLog::open(&log_path)
+            .line(&format!("==> Error: {}", ctx.secrets.mask(&e.to_string())));
+    }
+
+    let (status, exit_code) = match &result {
+        Ok(()) => (StepStatus::Passed, Some(0)),
+        Err(e) => {
+            let status = if step.allow_failure || stage.allow_failure {
+                StepStatus::FailedAllowed
+            } else {
+                StepStatus::Failed
+            };
+            (
+                status,
+                e.downcast_ref::<StepError>().and_then(|e| e.exit_code),
+            )
+        }
+    };
+    ctx.results
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(StepResult {
+            stage: stage.name.clone(),
+            index,
+            status,
+            exit_code,
+            duration: started.elapsed(),
+        });
+    result
+}
+
+/// Report the failure of a step with `allow_failure` and let the stage carry on as if the
+/// step had passed.
+fn allow_step_failure(
+    step: &Step,
+    result: Result<(), Box<dyn std::error::Error + Send + Sync>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match result {
+        Err(_) if step.allow_failure => {
+            println!(
+                "{}",
+                format!("Step failed (allowed): {}", step.display_name())
+                    .yellow()
+                    .bold()
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Check the `timeout`, `retry` and `if` settings of stages and steps, and that step
+/// output references and `needs_artifacts` name other steps' outputs and artifacts.
+fn validate_settings(config: &ForgeConfig) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    parse_timeout(config.defaults.timeout.as_deref(), "defaults")?;
+    for stage in &config.stages {
+        parse_timeout(
+            stage.defaults.timeout.as_deref(),
+            &format!("defaults of stage '{}'", stage.name),
+        )?;
+        if let Some(condition) = &stage.condition {
+            parse_condition(condition, &format!("stage '{}'", stage.name))?;
+        }
+    }
+    let steps = || {
+        config
+            .stages
+            .iter()
+            .flat_map(|stage| &stage.steps)
+            .chain(&config.steps)
+    };
+    let mut artifact_names = std::collections::HashSet::new();
+    for step in steps() {
+        let Some(name) = step.artifact_name() else {
+            continue;
+        };
+        let scope = format!("step '{}'", step.display_name());
+        if step.artifacts.as_ref().is_some_and(|a| a.paths.is_empty()) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Configuration validation failed: {scope}: artifact '{name}' has no paths\n\
+                     Hint: List the files or directories to keep, e.g. 'paths: [dist]'"
+                ),
+            )));
+        }
+        // Artifacts are stored in directories named after them. Separators are fine in step
+        // names, which are sanitized, but not in chosen names
+        let named = step.artifacts.as_ref().is_some_and(|a| a.name.is_some());
+        if matches!(name.trim(), "" | "." | "..") || (named && name.contains(['/', '\\'])) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Configuration validation failed: {scope}: invalid artifact name '{name}'\n\
+                     Hint: Artifact names cannot be empty, '.' or '..', nor contain '/' or '\\'; \
+                     without 'artifacts.name' the step name is used"
+                ),
+            )));
+        }
+        if !artifact_names.insert(cache::sanitize_key(name)) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Configuration validation failed: {scope}: artifact name '{name}' is already used\n\
+                     Hint: Give every artifact a unique 'name'; in a matrix, include a value \
+                     such as ${{{{ matrix.node }}}} in it"
+                ),
+            )));
+        }
+    }
+    for step in steps() {
+        let scope = format!("step '{}'", step.display_name());
+        parse_timeout(step.timeout.as_deref(), &scope)?;
+        if let Some(retry) = &step.retry {
+            retry.delay(&scope)?;
+        }
+        if let Some(condition) = &step.condition {
+            parse_condition(condition, &scope)?;
+        }
+
+        for name in &step.needs_artifacts {
+            let known = artifact_names.contains(&cache::sanitize_key(name));
+            if !known || step.artifact_name() == Some(name.as_str()) {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Configuration validation failed: {scope}: needs unknown artifact '{name}'\n\
+                         Hint: Name an artifact of another step; it is the step name unless \
+                         'artifacts.name' is set"
+                    ),
+                )));
+            }
+        }
+    }
+
+    // A reference names a step of the same stage, or one whose name no other stage uses,
+    // see `lookup_output`. Legacy top-level steps form a single stage.
+    let groups: Vec<(&str, &[Step])> = if config.stages.is_empty() {
+        vec![("default", config.steps.as_slice())]
+    } else {
+        config
+            .stages
+            .iter()
+            .map(|stage| (stage.name.as_str(), stage.steps.as_slice()))
+            .collect()
+    };
+    let has_step = |steps: &[Step], name: &str| steps.iter().any(|s| s.display_name() == name);
+    for (stage, steps) in &groups {
+        for step in *steps {
+            let scope = format!("step '{}'", step.display_name());
+            let fields = [&step.image, &step.working_dir, &step.command];
+            let references = fields
+                .into_iter()
+                .chain(step.env.values())
+                .flat_map(|text| outputs::references(text));
+            for (name, key) in references {
+                let elsewhere: Vec<&str> = groups
+                    .iter()
+                    .filter(|(other, other_steps)| other != stage && has_step(other_steps, &name))
+                    .map(|(other, _)| *other)
+                    .collect();
+                let problem = if name == step.display_name() {
+                    "refers to the step itself".to_string()
+                } else if has_step(steps, &name) || elsewhere.len() == 1 {
+                    continue;
+                } else if elsewhere.is_empty() {
+                    format!("refers to unknown step '{name}'")
+                } else {
+                    format!(
+                        "refers to step '{name}', which several stages have: {}",
+                        elsewhere.join(", ")
+                    )
+                };
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Configuration validation failed: {scope}: output '{key}' {problem}\n\
+                         Hint: Reference outputs of another step by its name, e.g. \
+                         ${{{{ steps.build.outputs.{key} }}}}, or ${{{{ steps['Build App'].outputs.{key} }}}} \
+                         for names with spaces. Steps of other stages can only be referenced \
+                         if no other stage has a step of that name"
+                    ),
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that stage and step names are unique, that every `depends_on` entry exists and
+/// that the dependencies do not form a cycle.
+fn validate_dependencies(
+    config: &ForgeConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fn invalid(scope: &str, e: GraphError) -> Box<dyn std::error::Error + Send + Sync> {
+        let hint = match e {
+            GraphError::Duplicate(_) => {
+                "Give every stage, and every step within a stage, a unique name"
+            }
+            GraphError::UnknownDependency { .. } => {
+                "Check the spelling in 'depends_on'; steps can only depend on steps of the same stage"
+            }
+            GraphError::Cycle(_) => "Remove one of the dependencies in the cycle",
+        };
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Configuration validation failed: {scope}: {e}\nHint: {hint}"),
+        ))
+    }
+
+    DependencyGraph::new(
+        config
+            .stages
+            .iter()
+            .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+    )
+    .map_err(|e| invalid("stages", e))?;
+
+    for stage in &config.stages {
+        DependencyGraph::new(
+            stage
+                .steps
+                .iter()
+                .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+        )
+        .map_err(|e| invalid(&format!("steps of stage '{}'", stage.name), e))?;
+    }
+
+    if config.stages.is_empty() {
+        DependencyGraph::new(
+            config
+                .steps
+                .iter()
+                .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+        )
+        .map_err(|e| invalid("steps", e))?;
+    }
+
+    Ok(())
+}
+
+/// Run all steps of a stage.
+async fn run_stage(
+    ctx: &RunContext<'_>,
+    stage: &Stage,
+    prefix_logs: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    run_steps(ctx.git, stage, prefix_logs, |index, prefix| {
+        run_recorded(ctx, stage, index, prefix)
+    })
+    .await
+}
+
+/// Schedule the steps of a stage, calling `run` with the position and log prefix of each
+/// step that is due.
+///
+/// Steps are ordered by their `depends_on`. In a sequential stage they run one after the
+/// other and the first failure stops the stage. In a parallel stage every step starts,
+/// each in its own container, as soon as its dependencies have succeeded; the stage only
+/// reports failure once every step has settled, so one failing step does not cut the
+/// output of its siblings short.
+async fn run_steps<'s, F, Fut>(
+    git: &git::GitInfo,
+    stage: &'s Stage,
+    prefix_logs: bool,
+    run: F,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(usize, Option<&'s str>) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let graph = DependencyGraph::new(
+        stage
+            .steps
+            .iter()
+            .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+    )
+    .map_err(|e| {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid step dependencies in stage '{}': {e}\n\
+                 Hint: Run 'forge-cli validate' to check your configuration",
+                stage.name
+            ),
+        ))
+    })?;
+
+    if !stage.parallel {
+        // Once a step failed, only steps whose condition asks for it still run
+        let mut failures = Vec::new();
+        let mut statuses = vec![Status::Skipped; graph.len()];
+        for index in graph.topological_order() {
+            let step = &stage.steps[index];
+            let failed = !failures.is_empty();
+            let blocked_by: Vec<&str> = graph
+                .dependencies(index)
+                .iter()
+                .filter(|&&dep| statuses[dep] != Status::Succeeded)
+                .map(|&dep| graph.name(dep))
+                .collect();
+            let success = blocked_by.is_empty() && !failed;
+            if !should_run(git, step.condition.as_deref(), success, failed, &step.env)? {
+                let reason = skip_reason(step.condition.as_deref(), &blocked_by);
+                println!(
+                    "{}",
+                    format!("Skipping step {}: {reason}", step.display_name()).yellow()
+                );
+                continue;
+            }
+            let prefix = prefix_logs.then_some(step.display_name());
+            match allow_step_failure(step, run(index, prefix).await) {
+                Ok(()) => statuses[index] = Status::Succeeded,
+                Err(e) => {
+                    statuses[index] = Status::Failed;
+                    failures.push((step.display_name(), e));
+                }
+            }
+        }
+
+        if failures.len() <= 1 {
+            return failures.pop().map_or(Ok(()), |(_, e)| Err(e));
+        }
+        let message = format!(
+            "Stage '{}' failed: {} steps failed\n{}",
+            stage.name,
+            failures.len(),
+            failures
+                .iter()
+                .map(|(name, e)| format!("• {name}: {e}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        let errors = failures.into_iter().map(|(_, e)| e).collect();
+        return Err(Box::new(FailureSummary { message, errors }));
+    }
+
+    let limits = matrix_limits(stage.steps.iter().map(|s| s.job.as_ref()));
+    let mut failed_upstream = vec![false; graph.len()];
+    let outcomes = graph::execute(&graph, |index, upstream| {
+        let step = &stage.steps[index];
+        let limit = step.job.as_ref().and_then(|j| limits.get(&j.group));
+        let (blocked_by, failure) = upstream_state(&graph, index, &upstream, &mut failed_upstream);
+        let due = should_run(
+            git,
+            step.condition.as_deref(),
+            blocked_by.is_empty() && !failure,
+            failure,
+            &step.env,
+        );
+
+        let run = &run;
+        async move {
+            match due {
+                Ok(true) => {}
+                Ok(false) => {
+                    let reason = skip_reason(step.condition.as_deref(), &blocked_by);
+                    println!(
+                        "{}",
+                        format!("Skipping step {}: {reason}", step.display_name()).yellow()
+                    );
+                    return Outcome::Skipped;
+                }
+                Err(e) => return Outcome::Failed(e),
+            }
+            let _permit = match limit {
+                Some(limit) => limit.acquire().await.ok(),
+                None => None,
+            };
+
+            let prefix = Some(step.display_name());
+            match allow_step_failure(step, run(index, prefix).await) {
+                Ok(()) => Outcome::Succeeded,
+                Err(e) => Outcome::Failed(e),
+            }
+        }
+    })
+    .await;
+
+    let mut failures = Vec::new();
+    let mut errors = Vec::new();
+    let mut skipped = Vec::new();
+    for (step, outcome) in stage.steps.iter().zip(outcomes) {
+        match outcome {
+            Outcome::Failed(e) => {
+                failures.push(format!("• {}: {e}", step.display_name()));
+                errors.push(e);
+            }
+            Outcome::Skipped => skipped.push(step.display_name()),
+            Outcome::Succeeded => {}
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut message = format!(
+        "Stage '{}' failed: {} of {} parallel steps failed\n{}",
+        stage.name,
+        failures.len(),
+        stage.steps.len(),
+        failures.join("\n")
+    );
+    if !skipped.is_empty() {
+        message.push_str(&format!(
+            "\nSkipped steps (dependencies failed): {}",
+            skipped.join(", ")
+        ));
+    }
+    Err(Box::new(FailureSummary { message, errors }))
+}
+
+/// Run all stages of the pipeline in dependency order. `env` is the top-level `env`.
+async fn run_pipeline(
+    ctx: &RunContext<'_>,
+    stages: &[Stage],
+    env: &std::collections::HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    run_stages(ctx.git, stages, env, |index, prefix_logs| {
+        run_stage(ctx, &stages[index], prefix_logs)
+    })
+    .await
+}
+
+/// Schedule the stages of the pipeline, calling `run` with the position of each stage that
+/// is due and whether its output needs the step name as prefix. Stage conditions see the
+/// top-level `env` merged with the `env` of the stage's defaults.
+///
+/// A stage starts as soon as every stage it `depends_on` has succeeded, so independent
+/// stages run concurrently. Stages downstream of a failure are skipped, while unrelated
+/// branches of the graph still run to completion.
+async fn run_stages<F, Fut>(
+    git: &git::GitInfo,
+    stages: &[Stage],
+    env: &std::collections::HashMap<String, String>,
+    run: F,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    F: Fn(usize, bool) -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let graph = DependencyGraph::new(
+        stages
+            .iter()
+            .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+    )
+    .map_err(|e| {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid stage dependencies: {e}\n\
+                 Hint: Run 'forge-cli validate' to check your configuration"
+            ),
+        ))
+    })?;
+
+    // Output of concurrent stages interleaves, so tag it with the step name
+    let prefix_logs = !graph.is_chain();
+
+    let limits = matrix_limits(stages.iter().map(|s| s.job.as_ref()));
+    let mut failed_upstream = vec![false; graph.len()];
+    let outcomes = graph::execute(&graph, |index, upstream| {
+        let stage = &stages[index];
+        let limit = stage.job.as_ref().and_then(|j| limits.get(&j.group));
+        let (blocked_by, failure) = upstream_state(&graph, index, &upstream, &mut failed_upstream);
+        let mut stage_env = env.clone();
+        stage_env.extend(stage.defaults.env.clone());
+        let due = should_run(
+            git,
+            stage.condition.as_deref(),
+            blocked_by.is_empty() && !failure,
+            failure,
+            &stage_env,
+        );
+
+        let run = &run;
+        async move {
+            match due {
+                Ok(true) => {}
+                Ok(false) => {
+                    let reason = skip_reason(stage.condition.as_deref(), &blocked_by);
+                    println!(
+                        "{}",
+                        format!("Skipping stage {}: {reason}", stage.name).yellow()
+                    );
+                    return Outcome::Skipped;
+                }
+                Err(e) => return Outcome::Failed(e),
+            }
+            let _permit = match limit {
+                Some(limit) => limit.acquire().await.ok(),
+                None => None,
+            };
+
+            println!("{}", format!("Stage: {}", stage.name).cyan().bold());
+            match run(index, prefix_logs).await {
+                Ok(()) => Outcome::Succeeded,
+                Err(_) if stage.allow_failure => {
+                    println!(
+                        "{}",
+                        format!("Stage failed (allowed): {}", stage.name)
+                            .yellow()
+                            .bold()
+                    );
+                    Outcome::Succeeded
+                }
+                Err(e) => {
+                    println!("{}", format!("Stage failed: {}", stage.name).red().bold());
+                    Outcome::Failed(e)
+                }
+            }
+        }
+    })
+    .await;
+
+    let mut failures = Vec::new();
+    let mut errors = Vec::new();
+    let mut skipped = Vec::new();
+    for (stage, outcome) in stages.iter().zip(outcomes) {
+        match outcome {
+            Outcome::Failed(e) => {
+                failures.push(format!("Stage '{}' failed: {e}", stage.name));
+                errors.push(e);
+            }
+            Outcome::Skipped => skipped.push(stage.name.as_str()),
+            Outcome::Succeeded => {}
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut message = failures.join("\n");
+    if !skipped.is_empty() {
+        message.push_str(&format!(
+            "\nSkipped stages (dependencies failed): {}",
+            skipped.join(", ")
+        ));
+    }
+    Err(Box::new(FailureSummary { message, errors }))
+}
+
 /// Create an example forge.yaml file.
 fn create_example_config(
     path: &str,
@@ -525,6 +2450,7 @@ cache:
 secrets:
   - name: API_TOKEN
     env_var: FORGE_API_TOKEN
+    optional: true
 "#;
 
     let mut file = File::create(path).map_err(|e| {
@@ -562,7 +2488,379 @@ secrets:
     );
     println!("Edit this file to configure your pipeline.");
 
-    Ok(())
+    Ok(())
+}
+
+/// Project directory of the configuration file given with `--file`.
+fn project_of(file: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(project_dir_of(Path::new(file)).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to locate the project of '{file}': {e}\n\
+                 Hint: Pass the project's configuration file with --file"
+            ),
+        )
+    })?)
+}
+
+/// Colour a run or step status for listings, padded to `width`.
+fn colored_status(status: &str, width: usize) -> ColoredString {
+    let text = format!("{status:<width$}");
+    match status {
+        "passed" | "reused" => text.green(),
+        "failed" => text.red(),
+        "skipped" => text.dimmed(),
+        _ => text.yellow(),
+    }
+}
+
+/// `forge-cli history`: the most recent runs, newest first.
+fn show_history(file: &str, limit: usize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let project_dir = project_of(file)?;
+    let runs = runs::list(&project_dir)?;
+    if runs.is_empty() {
+        println!(
+            "No recorded runs in {}",
+            runs::runs_dir(&project_dir).display()
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        format!("Runs of {}", project_dir.display()).cyan().bold()
+    );
+    for run in runs.iter().take(limit) {
+        let record = &run.record;
+        let started = chrono::DateTime::parse_from_rfc3339(&record.started_at)
+            .map(|t| {
+                t.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let duration = record
+            .duration_secs
+            .map(|secs| units::format_duration(std::time::Duration::from_secs_f64(secs)))
+            .unwrap_or_else(|| "-".to_string());
+        let sha = record
+            .git_sha
+            .as_deref()
+            .map_or("-", |sha| &sha[..sha.len().min(7)]);
+        let mut line = format!(
+            "  {}  {}  {started:<16}  {duration:>7}  {:<20} {sha}",
+            record.id,
+            colored_status(&record.status.to_string(), 7),
+            record.git_branch.as_deref().unwrap_or("-"),
+        );
+        let failed: Vec<&str> = record
+            .steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Failed)
+            .map(|step| step.name.as_str())
+            .collect();
+        if !failed.is_empty() {
+            line.push_str(&format!(
+                "  {}",
+                format!("failed: {}", failed.join(", ")).red()
+            ));
+        }
+        println!("{line}");
+    }
+    if runs.len() > limit {
+        println!("{} older runs not shown, see --limit", runs.len() - limit);
+    }
+    Ok(())
+}
+
+/// `forge-cli logs`: replay the stored logs of a run, step by step.
+fn show_logs(
+    file: &str,
+    run: Option<&str>,
+    step: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let project_dir = project_of(file)?;
+    let run = runs::find(&project_dir, run)?;
+    let record = &run.record;
+
+    // The name of a step with a matrix selects all of its copies
+    let selected: Vec<&runs::StepRecord> = record
+        .steps
+        .iter()
+        .filter(|s| {
+            step.is_none_or(|name| s.name == name || s.name.starts_with(&format!("{name} (")))
+        })
+        .collect();
+    if let (Some(name), true) = (step, selected.is_empty()) {
+        let names: Vec<&str> = record.steps.iter().map(|s| s.name.as_str()).collect();
+        return Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "Run {} has no step '{name}'\n\
+                 Available steps: {}",
+                record.id,
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            ),
+        )));
+    }
+
+    println!(
+        "{} {}",
+        format!("Run {}", record.id).cyan().bold(),
+        colored_status(&record.status.to_string(), 0)
+    );
+    let colorize = colored::control::SHOULD_COLORIZE.should_colorize();
+    for step in selected {
+        let mut details = colored_status(&step.status.to_string(), 0).to_string();
+        if let Some(secs) = step.duration_secs {
+            let duration = units::format_duration(std::time::Duration::from_secs_f64(secs));
+            details.push_str(&format!(", {duration}"));
+        }
+        println!(
+            "{} ({details})",
+            format!("── {} / {}", step.stage, step.name).cyan().bold()
+        );
+        let Some(log) = &step.log else {
+            continue;
+        };
+        let path = run.dir.join(log);
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Failed to read log '{}': {e}", path.display()),
+            )
+        })?;
+        for line in contents.lines() {
+            if let Some(header) = line.strip_prefix("==> ") {
+                println!("{}", header.yellow());
+            } else if colorize {
+                println!("{line}");
+            } else {
+                println!("{}", runs::strip_ansi(line));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handle the `forge cache` subcommands.
+fn manage_cache(command: CacheCommand) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let root = cache::cache_root();
+    let project_cache = |file: &str| -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(cache::project_cache_dir(&project_of(file)?))
+    };
+    let collect = |file: &str,
+                   all: bool|
+     -> Result<Vec<cache::EntryInfo>, Box<dyn std::error::Error + Send + Sync>> {
+        let dirs = if all {
+            cache::list_projects(&root)?
+        } else {
+            vec![project_cache(file)?]
+        };
+        let mut entries = Vec::new();
+        for dir in dirs {
+            entries.extend(cache::list_entries(&dir)?);
+        }
+        Ok(entries)
+    };
+    let age = |entry: &cache::EntryInfo| {
+        units::format_duration(entry.last_used.elapsed().unwrap_or_default())
+    };
+
+    match command {
+        CacheCommand::List { file, all } => {
+            let location = if all {
+                root.clone()
+            } else {
+                project_cache(&file)?
+            };
+            let entries = collect(&file, all)?;
+            if entries.is_empty() {
+                println!("No cache entries in {}", location.display());
+                return Ok(());
+            }
+
+            println!(
+                "{}",
+                format!("Cache entries in {}", location.display())
+                    .cyan()
+                    .bold()
+            );
+            let mut project = None;
+            for entry in &entries {
+                if all && project != Some(&entry.project) {
+                    println!("{}:", entry.project);
+                    project = Some(&entry.project);
+                }
+                println!(
+                    "  {:<40} {:>10}  used {} ago",
+                    entry.key,
+                    units::format_size(entry.size),
+                    age(entry)
+                );
+            }
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            println!(
+                "Total: {} in {} entries",
+                units::format_size(total),
+                entries.len()
+            );
+            Ok(())
+        }
+        CacheCommand::Show { key, file } => {
+            let dir = project_cache(&file)?;
+            let entry = cache::list_entries(&dir)?
+                .into_iter()
+                .find(|e| e.key == key)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!(
+                            "No cache entry '{key}' in {}\n\
+                             Hint: Run 'forge-cli cache list' to see the available entries",
+                            dir.display()
+                        ),
+                    )
+                })?;
+
+            println!("{}", format!("Cache entry '{}'", entry.key).cyan().bold());
+            println!("Location: {}", entry.dir.display());
+            println!("Size: {}", units::format_size(entry.size));
+            println!("Last used: {} ago", age(&entry));
+            if entry.archives.is_empty() {
+                println!("Directories: none");
+            } else {
+                println!("Directories:");
+                for (path, size) in &entry.archives {
+                    println!("  - {path} ({})", units::format_size(*size));
+                }
+            }
+            Ok(())
+        }
+        CacheCommand::Prune {
+            file,
+            all,
+            older_than,
+            max_size,
+            dry_run,
+        } => {
+            let invalid = |e: String| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{e}\nHint: Use durations like '12h' or '14d' and sizes like '500M' or '5G'"
+                    ),
+                )
+            };
+            let (config_age, config_size) = if Path::new(&file).exists() {
+                read_forge_config(Path::new(&file))?.cache.limits()?
+            } else {
+                (None, None)
+            };
+            let max_age = match older_than {
+                Some(text) => Some(units::parse_duration(&text).map_err(invalid)?),
+                None => config_age,
+            };
+            let max_size = match max_size {
+                Some(text) => Some(units::parse_size(&text).map_err(invalid)?),
+                None => config_size,
+            };
+            if max_age.is_none() && max_size.is_none() {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Nothing to prune by: no age or size limit given\n\
+                     Hint: Pass --older-than and/or --max-size, or set cache.max_age / cache.max_size in the configuration"
+                        .to_string(),
+                )));
+            }
+
+            let entries = collect(&file, all)?;
+            let evicted = cache::select_for_pruning(
+                &entries,
+                std::time::SystemTime::now(),
+                max_age,
+                max_size,
+            );
+            let mut freed = 0;
+            for &i in &evicted {
+                let entry = &entries[i];
+                let label = if all {
+                    format!("{}/{}", entry.project, entry.key)
+                } else {
+                    entry.key.clone()
+                };
+                let size = units::format_size(entry.size);
+                if dry_run {
+                    println!("Would remove {label} ({size}, used {} ago)", age(entry));
+                } else {
+                    std::fs::remove_dir_all(&entry.dir)?;
+                    println!("Removed {label} ({size}, used {} ago)", age(entry));
+                }
+                freed += entry.size;
+            }
+
+            let kept: u64 = entries.iter().map(|e| e.size).sum::<u64>() - freed;
+            let summary = format!(
+                "{} {} of {} entries, {} freed, {} kept",
+                if dry_run { "Would prune" } else { "Pruned" },
+                evicted.len(),
+                entries.len(),
+                units::format_size(freed),
+                units::format_size(kept)
+            );
+            println!("{}", summary.green().bold());
+            Ok(())
+        }
+        CacheCommand::Clear { key, file, all } => {
+            let (target, label) = match (&key, all) {
+                (Some(_), true) => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Cannot clear a single entry of every project\n\
+                         Hint: Drop either the key or --all"
+                            .to_string(),
+                    )));
+                }
+                (Some(key), false) => {
+                    let Some(dir) = cache::entry_dir(&project_cache(&file)?, key) else {
+                        return Err(Box::new(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "'{key}' is not a cache entry key\n\
+                                 Hint: Run 'forge-cli cache list' to see the available entries, \
+                                 or drop the key to clear the whole project cache"
+                            ),
+                        )));
+                    };
+                    if !dir.is_dir() {
+                        return Err(Box::new(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!(
+                                "No cache entry '{key}'\n\
+                                 Hint: Run 'forge-cli cache list' to see the available entries"
+                            ),
+                        )));
+                    }
+                    (dir, format!("cache entry '{key}'"))
+                }
+                (None, true) => (root.clone(), "all caches".to_string()),
+                (None, false) => (project_cache(&file)?, "project cache".to_string()),
+            };
+
+            if target.exists() {
+                std::fs::remove_dir_all(&target)?;
+                println!("{}", format!("Cleared {label}").green().bold());
+            } else {
+                println!("Nothing to clear in {}", target.display());
+            }
+            Ok(())
+        }
+    }
 }
 
 async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -582,6 +2880,13 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             cache,
             no_cache,
             stage,
+            from,
+            until,
+            only,
+            skip,
+            changed_since,
+            vars,
+            rerun_failed,
         }) => {
             println!("{}", "FORGE Pipeline Runner".cyan().bold());
 
@@ -598,7 +2903,38 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 )));
             }
 
+            // The workspace is the directory holding the configuration file
+            let project_dir = project_dir_of(config_path)?;
+            let git = git::GitInfo::new(&project_dir, &changed_since);
+            let run_id = new_run_id();
+
             let mut config = read_forge_config(config_path)?;
+            apply_defaults(&mut config);
+            matrix::expand(&mut config)?;
+            interpolate_config(&mut config, &vars, &run_id, &project_dir, &git)?;
+            validate_dependencies(&config)?;
+            validate_settings(&config)?;
+            let secrets = Secrets::resolve(&config.secrets)?;
+            let resolved_config = serde_yaml::to_string(&config)?;
+
+            let previous_run = match rerun_failed {
+                Some(id) => {
+                    let id = Some(id.as_str()).filter(|id| !id.is_empty());
+                    Some(runs::find(&project_dir, id)?)
+                }
+                None => None,
+            };
+            if let Some(previous) = &previous_run
+                && previous.record.status == runs::RunStatus::Passed
+            {
+                println!(
+                    "{}",
+                    format!("Run {} passed, nothing to re-run", previous.record.id)
+                        .green()
+                        .bold()
+                );
+                return Ok(());
+            }
 
             // Override cache settings if specified
             if cache {
@@ -650,31 +2986,39 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                     steps: config.steps.clone(),
                     parallel: false,
                     depends_on: vec![],
+                    allow_failure: false,
+                    condition: None,
+                    matrix: None,
+                    defaults: Defaults::default(),
+                    needs_artifacts: vec![],
+                    job: None,
                 });
             }
 
-            // Filter stages if a specific stage is requested
-            if let Some(stage_name) = stage {
-                let available_stages: Vec<String> =
-                    config.stages.iter().map(|s| s.name.clone()).collect();
-                config.stages.retain(|s| s.name == stage_name);
-                if config.stages.is_empty() {
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::NotFound,
-                        format!(
-                            "Stage '{}' not found in configuration\n\
-                             Available stages: {}\n\
-                             Hint: Check your forge.yaml file for correct stage names",
-                            stage_name,
-                            if available_stages.is_empty() {
-                                "none".to_string()
-                            } else {
-                                available_stages.join(", ")
-                            }
-                        ),
-                    )));
+            // Keep only the selected part of the pipeline
+            let selection = select::Selection {
+                stage: stage.as_deref(),
+                from: from.as_deref(),
+                until: until.as_deref(),
+                only: &only,
+                skip: &skip,
+            };
+            selection.apply(&mut config.stages)?;
+
+            // Take over the steps that passed in the earlier run
+            let rerun = match previous_run {
+                Some(previous) => {
+                    let rerun = rerun::Rerun::plan(previous, &config.stages)?;
+                    let total: usize = config.stages.iter().map(|s| s.steps.len()).sum();
+                    println!(
+                        "Re-running failed steps of run {}: reusing {} of {total} steps",
+                        rerun.run.record.id,
+                        rerun.len()
+                    );
+                    Some(rerun)
                 }
-            }
+                None => None,
+            };
 
             // Create a temporary directory for sharing data between containers
             let temp_dir = env::temp_dir().join(format!("forge-{}", uuid::Uuid::new_v4()));
@@ -700,6 +3044,29 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 }
             }
 
+            // Caches live outside the temporary directory so the next run can reuse them
+            let cache_dir = cache::project_cache_dir(&project_dir);
+            if config.cache.enabled {
+                if let Err(e) = std::fs::create_dir_all(&cache_dir) {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!(
+                            "Failed to create cache directory '{}': {}\n\
+                             Hint: Set FORGE_CACHE_DIR to a writable location, or run with --no-cache",
+                            cache_dir.display(),
+                            e
+                        ),
+                    )));
+                } else if verbose {
+                    println!("Using cache directory: {}", cache_dir.display());
+                }
+            }
+            let cache_entries = if config.cache.enabled {
+                cache::resolve_entries(&config.cache, &project_dir, &cache_dir)?
+            } else {
+                Vec::new()
+            };
+
             // Validate pipeline before execution
             if config.stages.is_empty() {
                 return Err(Box::new(std::io::Error::new(
@@ -711,27 +3078,96 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 )));
             }
 
+            // Keep the logs, artifacts and results of the run
+            let run_dir = runs::run_dir(&project_dir, &run_id);
+            let started_at = chrono::Local::now();
+            let mut record = runs::RunRecord {
+                id: run_id.clone(),
+                status: runs::RunStatus::Running,
+                started_at: started_at.to_rfc3339(),
+                finished_at: None,
+                duration_secs: None,
+                config_file: file.clone(),
+                git_branch: git.branch(),
+                git_sha: git.sha(),
+                error: None,
+                rerun_of: rerun.as_ref().map(|r| r.run.record.id.clone()),
+                steps: vec![],
+            };
+            std::fs::create_dir_all(&run_dir)
+                .and_then(|_| {
+                    std::fs::write(run_dir.join("config.yaml"), secrets.mask(&resolved_config))
+                })
+                .and_then(|_| record.save(&run_dir))
+                .map_err(|e| {
+                    Box::new(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!(
+                            "Failed to create run directory '{}': {e}\n\
+                             Hint: Check that the project directory is writable",
+                            run_dir.display()
+                        ),
+                    ))
+                })?;
+            println!("Run: {run_id}");
+
             // Run the pipeline
-            for stage in &config.stages {
-                println!("{}", format!("Stage: {}", stage.name).cyan().bold());
-
-                // Run steps in parallel or sequentially
-                if stage.parallel {
-                    // TODO: Implement parallel execution
-                    // For now, just run sequentially
-                    for step in &stage.steps {
-                        run_command_in_container(&docker, step, verbose, &config.cache, &temp_dir)
-                            .await?;
-                    }
-                } else {
-                    for step in &stage.steps {
-                        run_command_in_container(&docker, step, verbose, &config.cache, &temp_dir)
-                            .await?;
-                    }
-                }
+            let results = std::sync::Mutex::new(Vec::new());
+            let mut step_outputs = StepOutputs::new();
+            if let Some(rerun) = &rerun
+                && let Err(e) = rerun.restore(&config.stages, &run_dir, &mut step_outputs)
+            {
+                let _ = std::fs::remove_dir_all(&run_dir);
+                let _ = std::fs::remove_dir_all(&temp_dir);
+                return Err(Box::new(std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Failed to restore the steps of run {}: {e}\n\
+                         Hint: Run the whole pipeline without --rerun-failed",
+                        rerun.run.record.id
+                    ),
+                )));
+            }
+            let step_outputs = std::sync::Mutex::new(step_outputs);
+            let ctx = RunContext {
+                docker: &docker,
+                verbose,
+                cache_entries: &cache_entries,
+                temp_dir: &temp_dir,
+                run_dir: &run_dir,
+                cache_dir: &cache_dir,
+                secrets: &secrets,
+                workspace: config.workspace.as_ref(),
+                project_dir: &project_dir,
+                results: &results,
+                outputs: &step_outputs,
+                git: &git,
+                rerun: rerun.as_ref(),
+            };
+            let result = run_pipeline(&ctx, &config.stages, &config.env)
+                .await
+                .map_err(|e| secrets.mask_error(e));
+            let results = results.lock().unwrap_or_else(|e| e.into_inner());
+            print_summary(&config.stages, &results);
 
-                // No cleanup here, we'll do it after all stages are done
+            let finished_at = chrono::Local::now();
+            record.status = if result.is_ok() {
+                runs::RunStatus::Passed
+            } else {
+                runs::RunStatus::Failed
+            };
+            record.finished_at = Some(finished_at.to_rfc3339());
+            record.duration_secs = (finished_at - started_at)
+                .to_std()
+                .ok()
+                .map(|d| d.as_secs_f64());
+            record.error = result.as_ref().err().map(|e| e.to_string());
+            let step_outputs = step_outputs.lock().unwrap_or_else(|e| e.into_inner());
+            record.steps = step_records(&config.stages, &results, &step_outputs, &secrets);
+            if let Err(e) = record.save(&run_dir) {
+                eprintln!("Failed to save run record: {e}");
             }
+            println!("Logs and artifacts: {}", run_dir.display());
 
             // Clean up the temporary directory after the pipeline is done
             if verbose {
@@ -744,12 +3180,18 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             } else if verbose {
                 println!("Temporary directory removed successfully");
             }
+            result?;
 
             println!("{}", "Pipeline completed successfully!".green().bold());
             Ok(())
         }
         Some(Commands::Init { file, force }) => create_example_config(&file, force),
-        Some(Commands::Validate { file }) => {
+        Some(Commands::Cache { command }) => manage_cache(command),
+        Some(Commands::History { file, limit }) => show_history(&file, limit),
+        Some(Commands::Logs { run, step, file }) => {
+            show_logs(&file, run.as_deref(), step.as_deref())
+        }
+        Some(Commands::Validate { file, vars }) => {
             println!("{}", "Validating configuration file...".cyan().bold());
 
             let config_path = Path::new(&file);
@@ -764,7 +3206,12 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 )));
             }
 
-            let config = read_forge_config(config_path)?;
+            let mut config = read_forge_config(config_path)?;
+            apply_defaults(&mut config);
+            matrix::expand(&mut config)?;
+            let project_dir = project_dir_of(config_path)?;
+            let git = git::GitInfo::new(&project_dir, "HEAD");
+            interpolate_config(&mut config, &vars, &new_run_id(), &project_dir, &git)?;
 
             // Validate the configuration
             if config.stages.is_empty() && config.steps.is_empty() {
@@ -812,8 +3259,10 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 }
             }
 
-            // Check for circular dependencies in stages
-            // TODO: Implement circular dependency check
+            // Check for duplicate names, unknown dependencies and cycles
+            validate_dependencies(&config)?;
+            validate_settings(&config)?;
+            let (max_age, max_size) = config.cache.limits()?;
 
             println!("{}", "Configuration is valid!".green().bold());
 
@@ -829,18 +3278,52 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
             if config.cache.enabled {
                 println!("Cache: Enabled");
-                println!("Cached directories:");
-                for dir in &config.cache.directories {
-                    println!("  - {dir}");
+                if let Ok(project_dir) = project_dir_of(config_path) {
+                    println!(
+                        "Cache location: {}",
+                        cache::project_cache_dir(&project_dir).display()
+                    );
+                }
+                if !config.cache.directories.is_empty() {
+                    println!("Cached directories:");
+                    for dir in &config.cache.directories {
+                        println!("  - {dir}");
+                    }
+                }
+                for entry in &config.cache.entries {
+                    println!("Cache entry '{}':", entry.key);
+                    for dir in &entry.paths {
+                        println!("  - {dir}");
+                    }
+                }
+                if let Some(max_age) = max_age {
+                    println!("Cache max age: {}", units::format_duration(max_age));
+                }
+                if let Some(max_size) = max_size {
+                    println!("Cache max size: {}", units::format_size(max_size));
                 }
             } else {
                 println!("Cache: Disabled");
             }
 
+            if let Some(workspace) = &config.workspace {
+                let path = if workspace.path.is_empty() {
+                    "step working directory"
+                } else {
+                    workspace.path.as_str()
+                };
+                println!("Workspace: {:?} at {path}", workspace.mode);
+            }
+
             if !config.secrets.is_empty() {
                 println!("Secrets:");
                 for secret in &config.secrets {
-                    println!("  - {} (from {})", secret.name, secret.env_var);
+                    let note = match (secret.optional, env::var_os(&secret.env_var).is_some()) {
+                        (_, true) => "",
+                        (true, false) => ", optional, not set",
+                        (false, false) => ", not set",
+                    };
+                    println!("  - {} (from {}{note})", secret.name, secret.env_var);
                 }
             }
 
@@ -853,6 +3336,9 @@ async fn forge_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                  • forge-cli run      - Execute the pipeline\n\
                  • forge-cli init     - Create example config\n\
                  • forge-cli validate - Check config syntax\n\
+                 • forge-cli cache    - Inspect and prune the cache\n\
+                 • forge-cli history  - List recent runs\n\
+                 • forge-cli logs     - Show the logs of a run\n\
                  • forge-cli --help   - Show detailed help\n\
                  \n\
                  Hint: Start with 'forge-cli init' to create your first pipeline"
@@ -867,8 +3353,853 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     rt.block_on(async {
         if let Err(e) = forge_main().await {
             eprintln!("{}", format!("Error: {e}").red().bold());
-            std::process::exit(1);
+            std::process::exit(exit_code(e.as_ref()));
         }
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step_error(exit_code: Option<i64>) -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(StepError {
+            step: "test".to_string(),
+            exit_code,
+            timed_out: None,
+            attempts: 1,
+            command: "npm test".to_string(),
+            image: "node:20".to_string(),
+        })
+    }
+
+    #[test]
+    fn exit_code_comes_from_the_first_failed_step() {
+        let summary = FailureSummary {
+            message: "two steps failed".to_string(),
+            errors: vec![step_error(Some(3)), step_error(Some(2))],
+        };
+        assert_eq!(exit_code(&summary), 3);
+        assert_eq!(exit_code(&std::io::Error::other("config error")), 1);
+    }
+
+    #[test]
+    fn exit_code_falls_back_to_one_when_out_of_range() {
+        assert_eq!(exit_code(step_error(None).as_ref()), 1);
+        assert_eq!(exit_code(step_error(Some(300)).as_ref()), 1);
+        assert_eq!(exit_code(step_error(Some(137)).as_ref()), 137);
+    }
+
+    #[test]
+    fn timed_out_steps_exit_with_124() {
+        let error = StepError {
+            step: "integration".to_string(),
+            exit_code: Some(143),
+            timed_out: Some(std::time::Duration::from_secs(600)),
+            attempts: 1,
+            command: "make it".to_string(),
+            image: "alpine".to_string(),
+        };
+        assert_eq!(exit_code(&error), TIMEOUT_EXIT_CODE);
+        assert!(
+            error
+                .to_string()
+                .starts_with("Step 'integration' timed out after 10m00s")
+        );
+    }
+
+    #[test]
+    fn timeouts_must_be_positive_durations() {
+        assert_eq!(
+            parse_timeout(Some("90s"), "defaults").unwrap(),
+            Some(std::time::Duration::from_secs(90))
+        );
+        assert_eq!(parse_timeout(None, "defaults").unwrap(), None);
+        assert!(parse_timeout(Some("0s"), "defaults").is_err());
+        assert!(parse_timeout(Some("soon"), "step 'build'").is_err());
+    }
+
+    fn retry(yaml: &str) -> RetryPolicy {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_the_delay() {
+        let second = std::time::Duration::from_secs(1);
+        let policy = retry("{attempts: 4, delay: 1s, backoff: exponential}");
+        assert_eq!(policy.delay("step 'db'").unwrap(), second);
+        assert_eq!(policy.pause_after(second, 1), second);
+        assert_eq!(policy.pause_after(second, 3), second * 4);
+
+        let fixed = retry("{attempts: 4, delay: 1s}");
+        assert_eq!(fixed.pause_after(second, 3), second);
+        assert!(retry("{attempts: 0}").delay("step 'db'").is_err());
+        assert!(
+            retry("{attempts: 2, delay: later}")
+                .delay("step 'db'")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn retries_only_listed_exit_codes() {
+        let failure = |exit_code, timed_out| StepError {
+            step: "db".to_string(),
+            exit_code,
+            timed_out,
+            attempts: 1,
+            command: "make it".to_string(),
+            image: "alpine".to_string(),
+        };
+        let any = retry("{attempts: 3}");
+        assert!(any.retries(&failure(Some(1), None)));
+
+        let listed = retry("{attempts: 3, on_exit_codes: [2, 124]}");
+        assert!(listed.retries(&failure(Some(2), None)));
+        assert!(!listed.retries(&failure(Some(1), None)));
+        assert!(!listed.retries(&failure(None, None)));
+        let timeout = Some(std::time::Duration::from_secs(5));
+        assert!(listed.retries(&failure(Some(137), timeout)));
+    }
+
+    #[test]
+    fn steps_inherit_stage_then_pipeline_defaults() {
+        let mut config: ForgeConfig = serde_yaml::from_str(
+            r#"
+defaults:
+  image: node:16-alpine
+  working_dir: /app
+  env: {CI: "true", LEVEL: pipeline}
+  timeout: 10m
+stages:
+  - name: build
+    defaults:
+      image: node:20-alpine
+      env: {LEVEL: stage}
+      shell: bash
+    steps:
+      - command: npm ci
+      - command: npm run lint
+        image: node:18
+        env: {LEVEL: step}
+        shell: /bin/sh
+        user: "1000"
+"#,
+        )
+        .unwrap();
+        apply_defaults(&mut config);
+
+        let steps = &config.stages[0].steps;
+        assert_eq!(steps[0].image, "node:20-alpine");
+        assert_eq!(steps[0].working_dir, "/app");
+        assert_eq!(steps[0].env["LEVEL"], "stage");
+        assert_eq!(steps[0].env["CI"], "true");
+        assert_eq!(steps[0].shell.as_deref(), Some("bash"));
+        assert_eq!(steps[0].timeout.as_deref(), Some("10m"));
+        assert_eq!(steps[0].user, None);
+
+        assert_eq!(steps[1].image, "node:18");
+        assert_eq!(steps[1].env["LEVEL"], "step");
+        assert_eq!(steps[1].user.as_deref(), Some("1000"));
+        assert_eq!(steps[1].shell.as_deref(), Some("/bin/sh"));
+        assert_eq!(steps[1].working_dir, "/app");
+    }
+
+    fn config(yaml: &str) -> ForgeConfig {
+        let mut config: ForgeConfig = serde_yaml::from_str(yaml).unwrap();
+        apply_defaults(&mut config);
+        matrix::expand(&mut config).unwrap();
+        config
+    }
+
+    #[test]
+    fn outputs_resolve_within_the_stage_first() {
+        let config = config(
+            r#"
+stages:
+  - name: build
+    steps:
+      - name: version
+        command: echo "v=1" >> "$FORGE_OUTPUT"
+  - name: test
+    matrix:
+      node: ["18", "20"]
+    depends_on: [build]
+    steps:
+      - name: unit
+        command: echo "report=${{ matrix.node }}" >> "$FORGE_OUTPUT"
+      - name: upload
+        command: upload ${{ steps.unit.outputs.report }} ${{ steps.version.outputs.v }}
+"#,
+        );
+        validate_settings(&config).unwrap();
+
+        let mut outputs = StepOutputs::new();
+        for (stage, step, value) in [
+            ("build", "version", "1"),
+            ("test (node=18)", "unit", "18"),
+            ("test (node=20)", "unit", "20"),
+        ] {
+            let key = if step == "version" { "v" } else { "report" };
+            let output = StepOutput {
+                step: step.to_string(),
+                values: std::collections::HashMap::from([(key.to_string(), value.to_string())]),
+            };
+            outputs.insert((stage.to_string(), 0), output);
+        }
+        let stage = &config.stages[1];
+        assert_eq!(stage.name, "test (node=18)");
+        assert_eq!(
+            lookup_output(&outputs, stage, "unit", "report").unwrap(),
+            "18"
+        );
+        assert_eq!(lookup_output(&outputs, stage, "version", "v").unwrap(), "1");
+        assert_eq!(lookup_output(&outputs, stage, "version", "other"), None);
+    }
+
+    #[test]
+    fn references_to_steps_of_several_stages_are_ambiguous() {
+        let config = config(
+            r#"
+stages:
+  - name: test
+    matrix:
+      node: ["18", "20"]
+    steps:
+      - name: unit
+        command: echo "report=x" >> "$FORGE_OUTPUT"
+  - name: publish
+    depends_on: [test]
+    steps:
+      - name: upload
+        command: upload ${{ steps.unit.outputs.report }}
+"#,
+        );
+        let error = validate_settings(&config).unwrap_err().to_string();
+        assert!(error.contains(
+            "output 'report' refers to step 'unit', which several stages have: \
+             test (node=18), test (node=20)"
+        ));
+    }
+
+    #[test]
+    fn artifact_names_cannot_leave_the_artifacts_directory() {
+        for name in ["..", ".", "dist/../..", "a\\b"] {
+            let config = config(&format!(
+                "steps:\n  - name: build\n    command: make\n    artifacts:\n      name: '{name}'\n      paths: [dist]\n"
+            ));
+            let error = validate_settings(&config).unwrap_err().to_string();
+            assert!(error.contains("invalid artifact name"), "{name}: {error}");
+        }
+        let config = config(
+            "steps:\n  - name: build\n    command: make\n    artifacts:\n      name: dist.v1\n      paths: [dist]\n",
+        );
+        validate_settings(&config).unwrap();
+    }
+
+    #[test]
+    fn steps_without_a_timeout_use_the_default() {
+        let pipeline = config(
+            r#"
+defaults:
+  timeout: 30m
+steps:
+  - name: Integration Tests
+    command: make integration
+    timeout: 5m
+  - name: Lint
+    command: make lint
+"#,
+        );
+        validate_settings(&pipeline).unwrap();
+        let timeouts: Vec<_> = pipeline
+            .steps
+            .iter()
+            .map(|step| parse_timeout(step.timeout.as_deref(), "step").unwrap())
+            .collect();
+        let minute = std::time::Duration::from_secs(60);
+        assert_eq!(timeouts, [Some(minute * 5), Some(minute * 30)]);
+
+        let invalid = config("steps:\n  - command: make\n    timeout: 0s\n");
+        let error = validate_settings(&invalid).unwrap_err().to_string();
+        assert!(error.contains("step 'unnamed step'"), "{error}");
+    }
+
+    #[test]
+    fn step_retry_policies_are_read_from_the_config() {
+        let pipeline = config(
+            r#"
+steps:
+  - name: Integration Tests
+    command: make integration
+    retry:
+      attempts: 3
+      delay: 5s
+      backoff: exponential
+      on_exit_codes: [1, 124]
+"#,
+        );
+        validate_settings(&pipeline).unwrap();
+        let policy = pipeline.steps[0].retry.as_ref().unwrap();
+        let delay = policy.delay("step 'Integration Tests'").unwrap();
+        assert_eq!(policy.attempts, 3);
+        assert_eq!(delay, std::time::Duration::from_secs(5));
+        assert_eq!(policy.pause_after(delay, 2), delay * 2);
+        assert_eq!(policy.on_exit_codes, [1, 124]);
+
+        let invalid = config("steps:\n  - command: make\n    retry: {attempts: 2, delay: soon}\n");
+        assert!(validate_settings(&invalid).is_err());
+    }
+
+    #[test]
+    fn continue_on_error_is_an_alias_of_allow_failure() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: lint
+    allow_failure: true
+    steps:
+      - name: Lint
+        command: make lint
+  - name: test
+    steps:
+      - name: Unit Tests
+        command: make test
+      - name: Coverage Upload
+        command: make coverage
+        continue_on_error: true
+"#,
+        );
+        let [lint, test] = &pipeline.stages[..] else {
+            panic!("expected two stages");
+        };
+        assert!(lint.allow_failure && !lint.steps[0].allow_failure);
+        assert!(!test.allow_failure);
+        assert!(!test.steps[0].allow_failure);
+        assert!(test.steps[1].allow_failure);
+    }
+
+    #[test]
+    fn stage_and_step_conditions_are_parsed() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: deploy
+    if: git.branch == 'main'
+    steps:
+      - name: Deploy
+        command: make deploy
+      - name: Cleanup
+        command: make clean
+        if: always()
+"#,
+        );
+        validate_settings(&pipeline).unwrap();
+        let stage = &pipeline.stages[0];
+        let evaluate = |condition: &str, branch: &str, failed: bool| {
+            parse_condition(condition, "test")
+                .unwrap()
+                .evaluate(&expr::Context {
+                    success: !failed,
+                    failure: failed,
+                    env: &|_| None,
+                    branch: &|| Some(branch.to_string()),
+                    changed: &|_| false,
+                })
+        };
+        let condition = stage.condition.as_deref().unwrap();
+        assert!(evaluate(condition, "main", false));
+        assert!(!evaluate(condition, "feature", false));
+        assert!(!evaluate(condition, "main", true));
+        assert_eq!(stage.steps[0].condition, None);
+        let cleanup = stage.steps[1].condition.as_deref().unwrap();
+        assert!(evaluate(cleanup, "feature", true));
+
+        let invalid = config("steps:\n  - command: make\n    if: sometimes()\n");
+        let error = validate_settings(&invalid).unwrap_err().to_string();
+        assert!(error.contains("step 'unnamed step'"), "{error}");
+    }
+
+    #[test]
+    fn stage_matrices_expand_into_one_stage_per_combination() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: test
+    matrix:
+      node: [16, 18, 20]
+      os_image: [alpine, debian]
+      exclude:
+        - node: 16
+          os_image: debian
+      max_parallel: 2
+    steps:
+      - name: Unit Tests
+        image: node:${{ matrix.node }}-${{ matrix.os_image }}
+        command: npm test
+"#,
+        );
+        validate_settings(&pipeline).unwrap();
+        let images: Vec<(&str, &str)> = pipeline
+            .stages
+            .iter()
+            .map(|stage| (stage.name.as_str(), stage.steps[0].image.as_str()))
+            .collect();
+        assert_eq!(
+            images,
+            [
+                ("test (node=16, os_image=alpine)", "node:16-alpine"),
+                ("test (node=18, os_image=alpine)", "node:18-alpine"),
+                ("test (node=18, os_image=debian)", "node:18-debian"),
+                ("test (node=20, os_image=alpine)", "node:20-alpine"),
+                ("test (node=20, os_image=debian)", "node:20-debian"),
+            ]
+        );
+        let job = pipeline.stages[0].job.as_ref().unwrap();
+        assert_eq!((job.group.as_str(), job.max_parallel), ("test", Some(2)));
+    }
+
+    #[test]
+    fn variables_and_env_reach_the_steps() {
+        let mut pipeline = config(
+            r#"
+variables:
+  RUST_VERSION: "1.78"
+env:
+  CARGO_TERM_COLOR: always
+steps:
+  - name: Build
+    image: rust:${{ vars.RUST_VERSION }}
+    command: cargo build --release
+"#,
+        );
+        interpolate::apply(&mut pipeline, &[], &|_| None).unwrap();
+        assert_eq!(pipeline.steps[0].image, "rust:1.78");
+        assert_eq!(pipeline.steps[0].env["CARGO_TERM_COLOR"], "always");
+
+        let mut pipeline = config(
+            "variables: {RUST_VERSION: \"1.78\"}\nsteps:\n  - image: rust:${{ vars.RUST_VERSION }}\n    command: make\n",
+        );
+        let overrides = [("RUST_VERSION".to_string(), "1.80".to_string())];
+        interpolate::apply(&mut pipeline, &overrides, &|_| None).unwrap();
+        assert_eq!(pipeline.steps[0].image, "rust:1.80");
+    }
+
+    #[test]
+    fn artifacts_needed_by_a_stage_are_needed_by_its_steps() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: build
+    steps:
+      - name: Build
+        command: npm run build
+        working_dir: /app
+        artifacts:
+          name: dist
+          paths: [dist, coverage/*.xml]
+          when: always
+  - name: deploy
+    depends_on: [build]
+    needs_artifacts: [dist]
+    steps:
+      - name: Deploy
+        command: ./deploy.sh
+      - name: Notify
+        command: ./notify.sh
+        needs_artifacts: [dist]
+"#,
+        );
+        validate_settings(&pipeline).unwrap();
+        let build = &pipeline.stages[0].steps[0];
+        let artifacts = build.artifacts.as_ref().unwrap();
+        assert_eq!(build.artifact_name(), Some("dist"));
+        assert_eq!(artifacts.paths, ["dist", "coverage/*.xml"]);
+        assert_eq!(artifacts.when, ArtifactsWhen::Always);
+        for step in &pipeline.stages[1].steps {
+            assert_eq!(step.needs_artifacts, ["dist"]);
+        }
+
+        let unknown = config(
+            "steps:\n  - name: deploy\n    command: ./deploy.sh\n    needs_artifacts: [dist]\n",
+        );
+        let error = validate_settings(&unknown).unwrap_err().to_string();
+        assert!(error.contains("needs unknown artifact 'dist'"), "{error}");
+    }
+
+    fn read(yaml: &str) -> Result<ForgeConfig, Box<dyn std::error::Error + Send + Sync>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forge.yaml");
+        std::fs::write(&path, yaml).unwrap();
+        read_forge_config(&path)
+    }
+
+    #[test]
+    fn reads_steps_stages_cache_and_secrets() {
+        let config = read(
+            r#"
+version: "1.0"
+stages:
+  - name: build
+    steps:
+      - name: Install Dependencies
+        command: npm install
+        image: node:16-alpine
+        working_dir: /app
+        env:
+          NODE_ENV: development
+      - name: Build Project
+        command: npm run build
+        image: node:16-alpine
+        depends_on:
+          - Install Dependencies
+  - name: test
+    parallel: true
+    depends_on:
+      - build
+    steps:
+      - name: Run Unit Tests
+        command: npm test
+      - name: Run Linting
+        command: npm run lint
+cache:
+  enabled: true
+  directories:
+    - /app/node_modules
+    - /app/.cache
+secrets:
+  - name: API_TOKEN
+    env_var: FORGE_API_TOKEN
+"#,
+        )
+        .unwrap();
+        validate_dependencies(&config).unwrap();
+
+        let [build, test] = &config.stages[..] else {
+            panic!("expected two stages");
+        };
+        assert!(!build.parallel && build.depends_on.is_empty());
+        let install = &build.steps[0];
+        assert_eq!(install.name, "Install Dependencies");
+        assert_eq!(install.command, "npm install");
+        assert_eq!(install.image, "node:16-alpine");
+        assert_eq!(install.working_dir, "/app");
+        assert_eq!(install.env["NODE_ENV"], "development");
+        assert_eq!(build.steps[1].depends_on, ["Install Dependencies"]);
+        assert!(test.parallel);
+        assert_eq!(test.depends_on, ["build"]);
+        assert_eq!(test.steps.len(), 2);
+
+        assert!(config.cache.enabled);
+        assert_eq!(
+            config.cache.directories,
+            ["/app/node_modules", "/app/.cache"]
+        );
+        assert_eq!(config.secrets[0].name, "API_TOKEN");
+        assert_eq!(config.secrets[0].env_var, "FORGE_API_TOKEN");
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let config = read("# Empty config\n").unwrap();
+        assert_eq!(config.version, "1.0");
+        assert!(config.steps.is_empty() && config.stages.is_empty());
+        assert!(!config.cache.enabled && config.cache.directories.is_empty());
+        assert!(config.secrets.is_empty());
+
+        let config = read("steps:\n  - name: A step\n    command: echo \"Hello\"\n").unwrap();
+        assert_eq!(config.steps[0].image, "");
+        assert!(!config.cache.enabled);
+    }
+
+    #[test]
+    fn invalid_config_files_are_rejected() {
+        let missing_command = read("steps:\n  - name: Invalid Step\n    image: node:16-alpine\n");
+        assert!(missing_command.is_err());
+
+        let bad_indent = read(
+            "steps:\n  name: Invalid Syntax\n  image: node:16-alpine\n    working_dir: /app\n",
+        );
+        let error = bad_indent.unwrap_err().to_string();
+        assert!(
+            error.starts_with("Invalid YAML configuration in"),
+            "{error}"
+        );
+
+        let error = read_forge_config(Path::new("/nonexistent/forge.yaml"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("forge-cli init"), "{error}");
+    }
+
+    #[test]
+    fn stages_may_depend_on_stages_declared_later() {
+        let config = read(
+            r#"
+stages:
+  - name: test
+    steps: []
+    depends_on: [setup]
+  - name: setup
+    steps: []
+  - name: build
+    steps: []
+    depends_on: [test]
+"#,
+        )
+        .unwrap();
+        validate_dependencies(&config).unwrap();
+
+        let config =
+            read("stages:\n  - name: test\n    steps: []\n    depends_on: [non_existent]\n")
+                .unwrap();
+        let error = validate_dependencies(&config).unwrap_err().to_string();
+        assert!(
+            error.contains("Configuration validation failed: stages:"),
+            "{error}"
+        );
+        assert!(error.contains("non_existent"), "{error}");
+    }
+
+    /// Run the steps of `stage` through `run_steps`, failing the ones named in `failing`.
+    /// Returns the steps that ran, in the order they started, and the stage's error.
+    async fn schedule(stage: &Stage, failing: &[&str]) -> (Vec<String>, Option<String>) {
+        let git = git::GitInfo::new(Path::new("."), "HEAD");
+        let ran = std::sync::Mutex::new(Vec::new());
+        let result = run_steps(&git, stage, false, |index, _| {
+            let name = stage.steps[index].display_name();
+            ran.lock().unwrap().push(name.to_string());
+            let failed = failing.contains(&name);
+            async move {
+                if failed {
+                    Err(std::io::Error::other("exit code 1").into())
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .await;
+        (
+            ran.into_inner().unwrap(),
+            result.err().map(|e| e.to_string()),
+        )
+    }
+
+    #[tokio::test]
+    async fn parallel_steps_skip_only_what_depends_on_a_failure() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: test
+    parallel: true
+    steps:
+      - name: compile
+        command: make
+      - name: lint
+        command: make lint
+      - name: unit
+        command: make test
+        depends_on: [compile]
+      - name: e2e
+        command: make e2e
+        depends_on: [unit]
+      - name: report
+        command: make report
+        depends_on: [e2e, lint]
+        if: failure()
+"#,
+        );
+        let (ran, error) = schedule(&pipeline.stages[0], &["compile"]).await;
+        assert_eq!(ran, ["compile", "lint", "report"]);
+        let error = error.unwrap();
+        assert!(error.starts_with("Stage 'test' failed: 1 of 5 parallel steps failed"));
+        assert!(error.ends_with("Skipped steps (dependencies failed): unit, e2e"));
+
+        let (ran, error) = schedule(&pipeline.stages[0], &[]).await;
+        assert_eq!(ran, ["compile", "lint", "unit", "e2e"]);
+        assert_eq!(error, None);
+    }
+
+    #[tokio::test]
+    async fn sequential_steps_follow_depends_on_and_stop_at_a_failure() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: build
+    steps:
+      - name: package
+        command: make dist
+        depends_on: [compile]
+      - name: compile
+        command: make
+      - name: check
+        command: make check
+      - name: publish
+        command: make publish
+      - name: cleanup
+        command: make clean
+        if: always()
+"#,
+        );
+        let stage = &pipeline.stages[0];
+        let (ran, error) = schedule(stage, &[]).await;
+        assert_eq!(ran, ["compile", "package", "check", "publish", "cleanup"]);
+        assert_eq!(error, None);
+
+        let (ran, error) = schedule(stage, &["check"]).await;
+        assert_eq!(ran, ["compile", "package", "check", "cleanup"]);
+        assert_eq!(error.as_deref(), Some("exit code 1"));
+
+        let (_, error) = schedule(stage, &["check", "cleanup"]).await;
+        assert!(
+            error
+                .unwrap()
+                .starts_with("Stage 'build' failed: 2 steps failed")
+        );
+
+        // A step skipped by its condition also skips the steps depending on it
+        let mut skipped = stage.clone();
+        skipped.steps[1].condition = Some("false".to_string());
+        let (ran, error) = schedule(&skipped, &[]).await;
+        assert_eq!(ran, ["check", "publish", "cleanup"]);
+        assert_eq!(error, None);
+    }
+
+    /// Run the stages of `pipeline` through `run_stages` and `run_steps`, failing the steps
+    /// named in `failing` with exit code 3. Returns the steps that ran and the pipeline's result.
+    async fn schedule_pipeline(
+        pipeline: &ForgeConfig,
+        failing: &[&str],
+    ) -> (
+        Vec<String>,
+        Result<(), Box<dyn std::error::Error + Send + Sync>>,
+    ) {
+        let git = git::GitInfo::new(Path::new("."), "HEAD");
+        let stages = &pipeline.stages;
+        let ran = std::sync::Mutex::new(Vec::new());
+        let result = run_stages(&git, stages, &pipeline.env, |index, prefix_logs| {
+            let stage = &stages[index];
+            let (git, ran) = (&git, &ran);
+            run_steps(git, stage, prefix_logs, move |index, _| {
+                let name = stage.steps[index].display_name();
+                ran.lock().unwrap().push(name.to_string());
+                let result = if failing.contains(&name) {
+                    Err(step_error(Some(3)))
+                } else {
+                    Ok(())
+                };
+                async move { result }
+            })
+        })
+        .await;
+        let mut ran = ran.into_inner().unwrap();
+        ran.sort();
+        (ran, result)
+    }
+
+    #[tokio::test]
+    async fn allowed_failures_do_not_fail_the_pipeline() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: lint
+    allow_failure: true
+    steps:
+      - name: eslint
+        command: npm run lint
+      - name: prettier
+        command: npm run format
+  - name: test
+    steps:
+      - name: unit
+        command: npm test
+      - name: coverage
+        command: npm run coverage
+        allow_failure: true
+      - name: report
+        command: npm run report
+  - name: deploy
+    depends_on: [lint, test]
+    steps:
+      - name: upload
+        command: ./deploy.sh
+"#,
+        );
+        let (ran, result) = schedule_pipeline(&pipeline, &["eslint", "coverage"]).await;
+        assert_eq!(ran, ["coverage", "eslint", "report", "unit", "upload"]);
+        assert!(result.is_ok());
+
+        let (ran, result) = schedule_pipeline(&pipeline, &["unit"]).await;
+        assert_eq!(ran, ["eslint", "prettier", "unit"]);
+        let error = result.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Skipped stages (dependencies failed): deploy")
+        );
+        assert_eq!(exit_code(error.as_ref()), 3);
+    }
+
+    #[tokio::test]
+    async fn stage_conditions_see_the_pipeline_and_stage_env() {
+        let mut pipeline = config(
+            r#"
+env:
+  DEPLOY: "true"
+stages:
+  - name: deploy
+    if: env.DEPLOY == 'true'
+    steps:
+      - name: upload
+        command: ./deploy.sh
+  - name: announce
+    if: env.DEPLOY == 'true'
+    defaults:
+      env:
+        DEPLOY: "false"
+    steps:
+      - name: notify
+        command: ./notify.sh
+"#,
+        );
+        interpolate::apply(&mut pipeline, &[], &|_| None).unwrap();
+        let (ran, result) = schedule_pipeline(&pipeline, &[]).await;
+        assert_eq!(ran, ["upload"]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn unnamed_steps_keep_their_own_outputs() {
+        let pipeline = config(
+            r#"
+stages:
+  - name: default
+    steps:
+      - command: echo "a=1" >> "$FORGE_OUTPUT"
+      - command: echo "a=2" >> "$FORGE_OUTPUT"
+"#,
+        );
+        let mut outputs = StepOutputs::new();
+        let mut results = Vec::new();
+        for index in 0..2 {
+            let values = [("a".to_string(), (index + 1).to_string())];
+            let output = StepOutput {
+                step: "unnamed step".to_string(),
+                values: values.into(),
+            };
+            outputs.insert(("default".to_string(), index), output);
+            results.push(StepResult {
+                stage: "default".to_string(),
+                index,
+                status: StepStatus::Passed,
+                exit_code: Some(0),
+                duration: std::time::Duration::ZERO,
+            });
+        }
+        let records = step_records(&pipeline.stages, &results, &outputs, &Secrets::default());
+        assert_eq!(records[0].outputs["a"], "1");
+        assert_eq!(records[1].outputs["a"], "2");
+        assert_ne!(records[0].log, records[1].log);
+    }
+}
@@ -0,0 +1,416 @@
+//! Expansion of `matrix:` blocks into one step or stage per combination of values.
+//!
+//! ```yaml
+//! matrix:
+//!   node: [16, 18, 20]
+//!   image: [alpine, debian]
+//!   exclude:
+//!     - node: 16
+//!       image: debian
+//! ```
+//!
+//! Each combination becomes a copy named e.g. `test (node=18, image=alpine)`, with
+//! `${{ matrix.node }}` replaced in its `image`, `command`, `working_dir`, `env` and
+//! artifact settings.
+//! `depends_on` entries naming the original step or stage are rewritten to depend on
+//! every copy.
+
+use crate::{ForgeConfig, Matrix, Step};
+use std::collections::HashMap;
+
+/// Where an expanded step or stage came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// Name of the step or stage before expansion
+    pub group: String,
+    /// Matrix values of this copy, in declaration order
+    pub values: Vec<(String, String)>,
+    /// Maximum number of copies of the group running at once
+    pub max_parallel: Option<usize>,
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Replace every step and stage that has a matrix by its copies.
+pub fn expand(config: &mut ForgeConfig) -> Result<(), Error> {
+    let mut stages = Vec::new();
+    let mut renamed = HashMap::new();
+    for mut stage in std::mem::take(&mut config.stages) {
+        let scope = format!("stage '{}'", stage.name);
+        stage.steps = expand_steps(std::mem::take(&mut stage.steps), &scope)?;
+
+        let Some(matrix) = stage.matrix.take() else {
+            stages.push(stage);
+            continue;
+        };
+        let mut names = Vec::new();
+        for values in combinations(&matrix).map_err(|e| invalid(&scope, &e))? {
+            let mut copy = stage.clone();
+            copy.name = job_name(&stage.name, &values);
+            for step in &mut copy.steps {
+                substitute_step(step, &values);
+            }
+            copy.job = Some(Job {
+                group: stage.name.clone(),
+                values,
+                max_parallel: matrix.max_parallel,
+            });
+            names.push(copy.name.clone());
+            stages.push(copy);
+        }
+        renamed.insert(stage.name, names);
+    }
+    for stage in &mut stages {
+        stage.depends_on = rewrite(&stage.depends_on, &renamed);
+    }
+    config.stages = stages;
+    config.steps = expand_steps(std::mem::take(&mut config.steps), "steps")?;
+
+    // Anything left refers to a value that no matrix defines
+    let steps = config
+        .stages
+        .iter()
+        .flat_map(|stage| &stage.steps)
+        .chain(&config.steps);
+    for step in steps {
+        let artifacts = step
+            .artifacts
+            .iter()
+            .flat_map(|a| a.name.iter().chain(&a.paths));
+        let fields = [&step.image, &step.command, &step.working_dir]
+            .into_iter()
+            .chain(step.env.values())
+            .chain(artifacts)
+            .chain(&step.needs_artifacts);
+        if let Some(reference) = fields.filter_map(|f| unresolved(f)).next() {
+            return Err(invalid(
+                &format!("step '{}'", step.display_name()),
+                &format!("'{reference}' does not name a matrix value of the step or its stage"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn expand_steps(steps: Vec<Step>, scope: &str) -> Result<Vec<Step>, Error> {
+    let mut expanded = Vec::new();
+    let mut renamed = HashMap::new();
+    for mut step in steps {
+        let Some(matrix) = step.matrix.take() else {
+            expanded.push(step);
+            continue;
+        };
+        let group = step.display_name().to_string();
+        let combinations =
+            combinations(&matrix).map_err(|e| invalid(&format!("{scope}: step '{group}'"), &e))?;
+        let mut names = Vec::new();
+        for values in combinations {
+            let mut copy = step.clone();
+            copy.name = job_name(&group, &values);
+            substitute_step(&mut copy, &values);
+            copy.job = Some(Job {
+                group: group.clone(),
+                values,
+                max_parallel: matrix.max_parallel,
+            });
+            names.push(copy.name.clone());
+            expanded.push(copy);
+        }
+        renamed.insert(step.name, names);
+    }
+    for step in &mut expanded {
+        step.depends_on = rewrite(&step.depends_on, &renamed);
+    }
+    Ok(expanded)
+}
+
+fn invalid(scope: &str, reason: &str) -> Error {
+    Box::new(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Configuration validation failed: {scope}: matrix: {reason}\n\
+             Hint: A matrix lists values per key, e.g. 'node: [18, 20]', optionally with \
+             'include', 'exclude' and 'max_parallel'"
+        ),
+    ))
+}
+
+/// Name of the copy for one combination, e.g. `test (node=18, image=alpine)`.
+fn job_name(name: &str, values: &[(String, String)]) -> String {
+    let values: Vec<String> = values.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    format!("{name} ({})", values.join(", "))
+}
+
+/// Replace dependencies on expanded items by dependencies on all of their copies.
+fn rewrite(depends_on: &[String], renamed: &HashMap<String, Vec<String>>) -> Vec<String> {
+    depends_on
+        .iter()
+        .flat_map(|dep| match renamed.get(dep) {
+            Some(names) => names.clone(),
+            None => vec![dep.clone()],
+        })
+        .collect()
+}
+
+/// All combinations of a matrix: the product of its axes without the excluded ones, plus
+/// the `include` entries.
+fn combinations(matrix: &Matrix) -> Result<Vec<Vec<(String, String)>>, String> {
+    let mut axes = Vec::new();
+    for (key, values) in &matrix.axes {
+        let key = scalar(key).ok_or("keys must be strings")?;
+        let values = values
+            .as_sequence()
+            .filter(|values| !values.is_empty())
+            .ok_or_else(|| format!("'{key}' must be a non-empty list of values"))?;
+        let values = values
+            .iter()
+            .map(|v| scalar(v).ok_or_else(|| format!("values of '{key}' must be scalars")))
+            .collect::<Result<Vec<_>, _>>()?;
+        axes.push((key, values));
+    }
+
+    let mut combinations: Vec<Vec<(String, String)>> = if axes.is_empty() {
+        Vec::new()
+    } else {
+        vec![Vec::new()]
+    };
+    for (key, values) in &axes {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((key.clone(), value.clone()));
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    for exclude in &matrix.exclude {
+        let exclude = entry(exclude)?;
+        if let Some((key, _)) = exclude
+            .iter()
+            .find(|(k, _)| !axes.iter().any(|(a, _)| a == k))
+        {
+            return Err(format!("exclude refers to unknown key '{key}'"));
+        }
+        combinations.retain(|combination| !contains_all(combination, &exclude));
+    }
+
+    // Like GitHub Actions: an include entry adds its extra keys to every combination whose
+    // original values it matches, or becomes a combination of its own if there is none
+    let original = combinations.len();
+    for include in &matrix.include {
+        let include = entry(include)?;
+        let (matching, extra): (Vec<_>, Vec<_>) = include
+            .iter()
+            .cloned()
+            .partition(|(k, _)| axes.iter().any(|(a, _)| a == k));
+        let mut extended = false;
+        for combination in combinations.iter_mut().take(original) {
+            if contains_all(combination, &matching) {
+                for (key, value) in &extra {
+                    match combination.iter_mut().find(|(k, _)| k == key) {
+                        Some((_, existing)) => *existing = value.clone(),
+                        None => combination.push((key.clone(), value.clone())),
+                    }
+                }
+                extended = true;
+            }
+        }
+        if !extended {
+            combinations.push(include);
+        }
+    }
+
+    if combinations.is_empty() {
+        return Err("no combinations left to run".to_string());
+    }
+    Ok(combinations)
+}
+
+fn entry(mapping: &serde_yaml::Mapping) -> Result<Vec<(String, String)>, String> {
+    mapping
+        .iter()
+        .map(|(k, v)| match (scalar(k), scalar(v)) {
+            (Some(k), Some(v)) => Ok((k, v)),
+            _ => Err("include and exclude entries must map keys to scalar values".to_string()),
+        })
+        .collect()
+}
+
+fn contains_all(combination: &[(String, String)], values: &[(String, String)]) -> bool {
+    values.iter().all(|value| combination.contains(value))
+}
+
+fn scalar(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn substitute_step(step: &mut Step, values: &[(String, String)]) {
+    for field in [&mut step.image, &mut step.command, &mut step.working_dir] {
+        *field = substitute(field, values);
+    }
+    for value in step.env.values_mut() {
+        *value = substitute(value, values);
+    }
+    if let Some(artifacts) = &mut step.artifacts {
+        let name = artifacts.name.iter_mut();
+        for field in name.chain(artifacts.paths.iter_mut()) {
+            *field = substitute(field, values);
+        }
+    }
+    for name in &mut step.needs_artifacts {
+        *name = substitute(name, values);
+    }
+}
+
+/// Replace `${{ matrix.KEY }}` references to the given values; others are left alone.
+fn substitute(text: &str, values: &[(String, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end + 2) else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let reference = &rest[start..end];
+        let key = reference[3..reference.len() - 2]
+            .trim()
+            .strip_prefix("matrix.");
+        match key.and_then(|key| values.iter().find(|(k, _)| k == key)) {
+            Some((_, value)) => result.push_str(value),
+            None => result.push_str(reference),
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The first `${{ matrix.KEY }}` reference left in `text`.
+fn unresolved(text: &str) -> Option<&str> {
+    let mut rest = text;
+    while let Some(start) = rest.find("${{") {
+        let end = start + rest[start..].find("}}")? + 2;
+        let reference = &rest[start..end];
+        if reference[3..reference.len() - 2]
+            .trim()
+            .starts_with("matrix.")
+        {
+            return Some(reference);
+        }
+        rest = &rest[end..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(yaml: &str) -> Matrix {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn names(combinations: Vec<Vec<(String, String)>>) -> Vec<String> {
+        combinations
+            .iter()
+            .map(|values| job_name("test", values))
+            .collect()
+    }
+
+    #[test]
+    fn product_without_excluded_combinations() {
+        let combinations = combinations(&matrix(
+            "{node: [16, 18], image: [alpine, debian], exclude: [{node: 16, image: debian}]}",
+        ))
+        .unwrap();
+        assert_eq!(
+            names(combinations),
+            [
+                "test (node=16, image=alpine)",
+                "test (node=18, image=alpine)",
+                "test (node=18, image=debian)"
+            ]
+        );
+    }
+
+    #[test]
+    fn include_extends_matches_or_adds_combinations() {
+        let combinations = combinations(&matrix(
+            "{node: [16, 18], include: [{node: 18, experimental: true}, {node: 21}]}",
+        ))
+        .unwrap();
+        assert_eq!(
+            names(combinations),
+            [
+                "test (node=16)",
+                "test (node=18, experimental=true)",
+                "test (node=21)"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_matrices() {
+        assert!(combinations(&matrix("{node: []}")).is_err());
+        assert!(combinations(&matrix("{node: 16}")).is_err());
+        assert!(combinations(&matrix("{max_parallel: 2}")).is_err());
+        assert!(combinations(&matrix("{node: [16], exclude: [{os: linux}]}")).is_err());
+        assert!(combinations(&matrix("{node: [16], exclude: [{node: 16}]}")).is_err());
+    }
+
+    #[test]
+    fn expands_steps_and_rewrites_dependencies() {
+        let mut config: ForgeConfig = serde_yaml::from_str(
+            r#"
+stages:
+  - name: test
+    matrix:
+      node: [18, 20]
+    steps:
+      - name: unit
+        image: node:${{ matrix.node }}
+        command: npm test -- --shard=${{ matrix.shard }}
+        matrix:
+          shard: [1, 2]
+  - name: report
+    depends_on: [test]
+    steps:
+      - command: echo done
+"#,
+        )
+        .unwrap();
+        expand(&mut config).unwrap();
+
+        let stage_names: Vec<&str> = config.stages.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(stage_names, ["test (node=18)", "test (node=20)", "report"]);
+        assert_eq!(
+            config.stages[2].depends_on,
+            ["test (node=18)", "test (node=20)"]
+        );
+
+        let step = &config.stages[1].steps[1];
+        assert_eq!(step.name, "unit (shard=2)");
+        assert_eq!(step.image, "node:20");
+        assert_eq!(step.command, "npm test -- --shard=2");
+        assert_eq!(step.job.as_ref().unwrap().group, "unit");
+    }
+
+    #[test]
+    fn unknown_matrix_values_are_reported() {
+        let mut config: ForgeConfig = serde_yaml::from_str(
+            "steps: [{name: unit, command: 'echo ${{ matrix.node }}', matrix: {os: [linux]}}]",
+        )
+        .unwrap();
+        let error = expand(&mut config).unwrap_err().to_string();
+        assert!(error.contains("${{ matrix.node }}"), "{error}");
+    }
+}
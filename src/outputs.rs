@@ -0,0 +1,142 @@
+//! Step outputs: values a step writes to the file named by `FORGE_OUTPUT`, which later
+//! steps reference as `${{ steps.NAME.outputs.KEY }}`.
+//!
+//! Names containing spaces or dots use the bracket form `${{ steps['Build App'].outputs.KEY }}`.
+
+use std::collections::HashMap;
+
+/// Environment variable holding the path of a step's output file inside the container.
+pub const OUTPUT_ENV: &str = "FORGE_OUTPUT";
+
+/// Parse the contents of an output file.
+///
+/// Each line is `KEY=VALUE`; a multi-line value is written as `KEY<<DELIMITER`, followed by
+/// the value's lines and a line holding only the delimiter. Other lines are ignored, and a
+/// key written twice keeps its last value.
+pub fn parse(contents: &str) -> HashMap<String, String> {
+    let mut outputs = HashMap::new();
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        if let Some((key, delimiter)) = line.split_once("<<") {
+            let delimiter = delimiter.trim();
+            let value: Vec<&str> = lines.by_ref().take_while(|l| *l != delimiter).collect();
+            outputs.insert(key.trim().to_string(), value.join("\n"));
+        } else if let Some((key, value)) = line.split_once('=')
+            && !key.trim().is_empty()
+        {
+            outputs.insert(key.trim().to_string(), value.to_string());
+        }
+    }
+    outputs
+}
+
+/// Step name and key of an output reference, given the text between `${{` and `}}`.
+pub fn parse_reference(reference: &str) -> Option<(String, String)> {
+    let rest = reference.trim().strip_prefix("steps")?;
+    let (step, rest) = if let Some(rest) = rest.strip_prefix('[') {
+        let quote = rest.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+        let (step, rest) = rest[1..].split_once(quote)?;
+        (step, rest.strip_prefix(']')?)
+    } else {
+        let rest = rest.strip_prefix('.')?;
+        let end = rest.find(".outputs.")?;
+        (&rest[..end], &rest[end..])
+    };
+    let key = rest.strip_prefix(".outputs.")?;
+    (!step.is_empty() && !key.is_empty() && !key.contains(char::is_whitespace))
+        .then(|| (step.to_string(), key.to_string()))
+}
+
+/// Output references in `text`, as `(step, key)` pairs.
+pub fn references(text: &str) -> Vec<(String, String)> {
+    let mut found = Vec::new();
+    let _ = substitute(text, |step, key| {
+        found.push((step.to_string(), key.to_string()));
+        Some(String::new())
+    });
+    found
+}
+
+/// Replace the output references in `text` with the values `lookup` gives for them.
+/// Fails with the first reference `lookup` has no value for.
+pub fn substitute(
+    text: &str,
+    mut lookup: impl FnMut(&str, &str) -> Option<String>,
+) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end + 2) else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let reference = &rest[start..end];
+        match parse_reference(&reference[3..reference.len() - 2]) {
+            Some((step, key)) => match lookup(&step, &key) {
+                Some(value) => result.push_str(&value),
+                None => return Err(reference.to_string()),
+            },
+            None => result.push_str(reference),
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_and_multi_line_values() {
+        let outputs = parse(
+            "version=1.2.3\nurl=http://x?a=b\n\nnotes<<EOF\nline 1\nline 2\nEOF\nversion=1.2.4\n",
+        );
+        assert_eq!(outputs["version"], "1.2.4");
+        assert_eq!(outputs["url"], "http://x?a=b");
+        assert_eq!(outputs["notes"], "line 1\nline 2");
+        assert_eq!(outputs.len(), 3);
+    }
+
+    #[test]
+    fn parses_references() {
+        let reference = |text| parse_reference(text).map(|(s, k)| format!("{s}/{k}"));
+        assert_eq!(
+            reference(" steps.build.outputs.version ").as_deref(),
+            Some("build/version")
+        );
+        assert_eq!(
+            reference("steps['Build App'].outputs.version").as_deref(),
+            Some("Build App/version")
+        );
+        assert_eq!(
+            reference(r#"steps["test (node=18)"].outputs.report"#).as_deref(),
+            Some("test (node=18)/report")
+        );
+        assert_eq!(reference("steps.build.version"), None);
+        assert_eq!(reference("steps.build.outputs."), None);
+        assert_eq!(reference("vars.build"), None);
+    }
+
+    #[test]
+    fn substitutes_known_outputs() {
+        let lookup =
+            |step: &str, key: &str| (step == "build" && key == "v").then(|| "2".to_string());
+        assert_eq!(
+            substitute("tag=${{ steps.build.outputs.v }} ${{ vars.X }}", lookup).unwrap(),
+            "tag=2 ${{ vars.X }}"
+        );
+        assert_eq!(
+            substitute("${{ steps.build.outputs.other }}", lookup).unwrap_err(),
+            "${{ steps.build.outputs.other }}"
+        );
+        assert_eq!(
+            references("${{ steps.a.outputs.x }}-${{ steps['b c'].outputs.y }}"),
+            [
+                ("a".to_string(), "x".to_string()),
+                ("b c".to_string(), "y".to_string())
+            ]
+        );
+    }
+}
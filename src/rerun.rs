@@ -0,0 +1,324 @@
+//! Re-running the failed part of an earlier run, for `forge-cli run --rerun-failed`.
+//!
+//! Steps that passed in the earlier run are not run again, unless something they depend on
+//! runs again: in a parallel stage that is the steps named in `depends_on`, in a sequential
+//! stage every earlier step, and across stages every step of the stages in `depends_on`.
+//! Everything else (failed steps, steps skipped because of a failure, failures allowed by
+//! `allow_failure`) runs again. Steps are matched by stage, position in the stage and name.
+//!
+//! Reused steps count as passed for the steps after them: their logs and artifacts are
+//! copied into the new run and their outputs are restored from `run.json`. Outputs holding
+//! a secret are stored masked, so steps with such outputs run again.
+
+use crate::graph::DependencyGraph;
+use crate::runs::{self, StepRecord};
+use crate::{Stage, StepOutput, StepOutputs, StepStatus, artifacts};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The steps of an earlier run that a new run takes over.
+pub struct Rerun {
+    /// The earlier run
+    pub run: runs::Run,
+    /// Records of the reused steps, by stage and position of the step in the stage
+    reused: HashMap<(String, usize), StepRecord>,
+}
+
+impl Rerun {
+    /// Decide which steps of `stages` can reuse their result from `run`.
+    pub fn plan(
+        run: runs::Run,
+        stages: &[Stage],
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        // Records are in configuration order, so they count the positions within a stage
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        let mut previous: HashMap<(&str, usize), &StepRecord> = HashMap::new();
+        for step in &run.record.steps {
+            let position = positions.entry(step.stage.as_str()).or_default();
+            if reusable(step) {
+                previous.insert((step.stage.as_str(), *position), step);
+            }
+            *position += 1;
+        }
+
+        let stage_graph = DependencyGraph::new(
+            stages
+                .iter()
+                .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+        )?;
+        let mut stage_reruns = vec![false; stages.len()];
+        let mut reused = HashMap::new();
+        for stage_index in stage_graph.topological_order() {
+            let stage = &stages[stage_index];
+            let upstream = stage_graph
+                .dependencies(stage_index)
+                .iter()
+                .any(|&dep| stage_reruns[dep]);
+            let graph = DependencyGraph::new(
+                stage
+                    .steps
+                    .iter()
+                    .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+            )?;
+            let mut reruns = vec![false; stage.steps.len()];
+            for index in graph.topological_order() {
+                let step = &stage.steps[index];
+                let record = previous
+                    .get(&(stage.name.as_str(), index))
+                    .filter(|record| record.name == step.display_name());
+                let rerun = record.is_none()
+                    || upstream
+                    || (!stage.parallel && stage_reruns[stage_index])
+                    || graph.dependencies(index).iter().any(|&dep| reruns[dep]);
+                reruns[index] = rerun;
+                stage_reruns[stage_index] |= rerun;
+                if let (false, Some(record)) = (rerun, record) {
+                    reused.insert((stage.name.clone(), index), (*record).clone());
+                }
+            }
+        }
+        Ok(Rerun { run, reused })
+    }
+
+    /// Record of a step, given by its stage and position, that is reused rather than run
+    /// again.
+    pub fn reused(&self, stage: &str, index: usize) -> Option<&StepRecord> {
+        self.reused.get(&(stage.to_string(), index))
+    }
+
+    /// Number of reused steps.
+    pub fn len(&self) -> usize {
+        self.reused.len()
+    }
+
+    /// Copy the logs and artifacts of the reused steps into `run_dir` and restore their
+    /// outputs.
+    pub fn restore(
+        &self,
+        stages: &[Stage],
+        run_dir: &Path,
+        outputs: &mut StepOutputs,
+    ) -> std::io::Result<()> {
+        for stage in stages {
+            for (index, step) in stage.steps.iter().enumerate() {
+                let Some(record) = self.reused(&stage.name, index) else {
+                    continue;
+                };
+                if let Some(log) = &record.log {
+                    let path = run_dir.join(runs::log_file(&stage.name, index, &record.name));
+                    copy_file(&self.run.dir.join(log), &path)?;
+                    runs::StepLog::open(&path)
+                        .line(&format!("==> Reused from run {}", self.run.record.id));
+                }
+                if let Some(name) = step.artifact_name() {
+                    let from = artifacts::artifact_dir(&self.run.dir.join("artifacts"), name);
+                    if from.is_dir() {
+                        let to = artifacts::artifact_dir(&run_dir.join("artifacts"), name);
+                        for file in crate::glob::find_files(&from, "**")? {
+                            let relative =
+                                file.strip_prefix(&from).map_err(std::io::Error::other)?;
+                            copy_file(&file, &to.join(relative))?;
+                        }
+                    }
+                }
+                if !record.outputs.is_empty() {
+                    let output = StepOutput {
+                        step: record.name.clone(),
+                        values: record.outputs.clone(),
+                    };
+                    outputs.insert((stage.name.clone(), index), output);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a step's result can be taken over: it passed, and none of its outputs is masked.
+fn reusable(step: &StepRecord) -> bool {
+    matches!(step.status, StepStatus::Passed | StepStatus::Reused)
+        && !step
+            .outputs
+            .values()
+            .any(|value| value.contains(crate::secrets::MASK))
+}
+
+fn copy_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(from, to).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stages() -> Vec<Stage> {
+        let config: crate::ForgeConfig = serde_yaml::from_str(
+            r#"
+stages:
+  - name: build
+    steps:
+      - name: compile
+        command: make
+        artifacts:
+          paths: [/app/dist]
+      - name: package
+        command: make dist
+  - name: test
+    parallel: true
+    depends_on: [build]
+    steps:
+      - name: unit
+        command: make test
+      - name: integration
+        command: make it
+      - name: report
+        command: make report
+        depends_on: [integration]
+  - name: deploy
+    depends_on: [test]
+    steps:
+      - name: upload
+        command: make upload
+"#,
+        )
+        .unwrap();
+        config.stages
+    }
+
+    fn record(stage: &str, name: &str, status: StepStatus) -> StepRecord {
+        StepRecord {
+            stage: stage.to_string(),
+            name: name.to_string(),
+            status,
+            exit_code: None,
+            duration_secs: None,
+            log: None,
+            outputs: HashMap::new(),
+        }
+    }
+
+    fn previous_run(dir: &Path, steps: Vec<StepRecord>) -> runs::Run {
+        runs::Run {
+            dir: dir.to_path_buf(),
+            record: runs::RunRecord {
+                id: "20240501-142233-3f9a1c".to_string(),
+                status: runs::RunStatus::Failed,
+                started_at: String::new(),
+                finished_at: None,
+                duration_secs: None,
+                config_file: "forge.yaml".to_string(),
+                git_branch: None,
+                git_sha: None,
+                error: None,
+                rerun_of: None,
+                steps,
+            },
+        }
+    }
+
+    #[test]
+    fn reruns_failed_steps_and_everything_downstream() {
+        let mut secret_output = record("test", "unit", StepStatus::Passed);
+        secret_output
+            .outputs
+            .insert("token".to_string(), "***".to_string());
+        let run = previous_run(
+            Path::new("."),
+            vec![
+                record("build", "compile", StepStatus::Passed),
+                record("build", "package", StepStatus::Reused),
+                secret_output,
+                record("test", "integration", StepStatus::Failed),
+                record("test", "report", StepStatus::Passed),
+                record("deploy", "upload", StepStatus::Skipped),
+            ],
+        );
+        let rerun = Rerun::plan(run, &stages()).unwrap();
+        assert!(rerun.reused("build", 0).is_some());
+        assert!(rerun.reused("build", 1).is_some());
+        assert!(rerun.reused("test", 0).is_none());
+        assert!(rerun.reused("test", 2).is_none());
+        assert_eq!(rerun.len(), 2);
+
+        // A failure early in a sequential stage reruns the steps after it
+        let run = previous_run(
+            Path::new("."),
+            vec![
+                record("build", "compile", StepStatus::FailedAllowed),
+                record("build", "package", StepStatus::Passed),
+            ],
+        );
+        assert_eq!(Rerun::plan(run, &stages()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn restores_logs_artifacts_and_outputs() {
+        let previous = tempfile::tempdir().unwrap();
+        let log = runs::log_file("build", 0, "compile");
+        runs::StepLog::open(&previous.path().join(&log)).line("compiled");
+        let artifact = artifacts::artifact_dir(&previous.path().join("artifacts"), "compile");
+        std::fs::create_dir_all(artifact.join("app/dist")).unwrap();
+        std::fs::write(artifact.join("app/dist/main.js"), "").unwrap();
+
+        let mut compile = record("build", "compile", StepStatus::Passed);
+        compile.log = Some(log.to_string_lossy().into_owned());
+        compile
+            .outputs
+            .insert("version".to_string(), "1.2.3".to_string());
+        let run = previous_run(previous.path(), vec![compile]);
+        let rerun = Rerun::plan(run, &stages()).unwrap();
+
+        let run_dir = tempfile::tempdir().unwrap();
+        let mut outputs = StepOutputs::new();
+        rerun
+            .restore(&stages(), run_dir.path(), &mut outputs)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(run_dir.path().join(&log)).unwrap(),
+            "compiled\n==> Reused from run 20240501-142233-3f9a1c\n"
+        );
+        assert!(
+            run_dir
+                .path()
+                .join("artifacts/compile/app/dist/main.js")
+                .is_file()
+        );
+        let key = ("build".to_string(), 0);
+        assert_eq!(outputs[&key].values["version"], "1.2.3");
+    }
+
+    #[test]
+    fn steps_are_matched_by_position() {
+        let config: crate::ForgeConfig = serde_yaml::from_str(
+            r#"
+stages:
+  - name: build
+    parallel: true
+    steps:
+      - command: make a
+      - command: make b
+"#,
+        )
+        .unwrap();
+        let run = previous_run(
+            Path::new("."),
+            vec![
+                record("build", "unnamed step", StepStatus::Failed),
+                record("build", "unnamed step", StepStatus::Passed),
+            ],
+        );
+        let rerun = Rerun::plan(run, &config.stages).unwrap();
+        assert!(rerun.reused("build", 0).is_none());
+        assert!(rerun.reused("build", 1).is_some());
+
+        // A different step at the same position runs again
+        let run = previous_run(
+            Path::new("."),
+            vec![record("build", "lint", StepStatus::Passed)],
+        );
+        assert_eq!(Rerun::plan(run, &config.stages).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,376 @@
+//! Records of pipeline runs, kept in `.forge/runs/<run-id>/` of the project:
+//!
+//! - `run.json`: status, timings, exit code and outputs of every step
+//! - `config.yaml`: the configuration as run, after matrix expansion and interpolation
+//! - `logs/<stage>/<NN>-<step>.log`: output of every step, numbered by its position in the
+//!   stage, with secrets masked
+//! - `artifacts/<name>/`: files collected by steps with `artifacts`
+//!
+//! `run.json` is written when the run starts and again when it ends, so an interrupted run
+//! still shows up as `running`. Logs keep the colours of the terminal output: stderr lines
+//! are stored in red, and ANSI codes written by the steps themselves are kept as they are.
+
+use crate::StepStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Directory of a project where FORGE keeps its own files. It is left out of the workspace
+/// copied into the containers and of `hashFiles()`, as it changes with every run.
+pub const FORGE_DIR: &str = ".forge";
+
+/// Directory holding the records of all runs of a project.
+pub fn runs_dir(project_dir: &Path) -> PathBuf {
+    project_dir.join(FORGE_DIR).join("runs")
+}
+
+/// Directory of one run.
+pub fn run_dir(project_dir: &Path, run_id: &str) -> PathBuf {
+    runs_dir(project_dir).join(run_id)
+}
+
+/// Log file of the step at position `index` of a stage, relative to the run directory.
+///
+/// The position keeps the files of unnamed steps, and of names that differ only in
+/// characters not allowed in file names, apart.
+pub fn log_file(stage: &str, index: usize, step: &str) -> PathBuf {
+    let name = crate::cache::sanitize_key(step);
+    Path::new("logs")
+        .join(crate::cache::sanitize_key(stage))
+        .join(format!("{:02}-{name}.log", index + 1))
+}
+
+/// Outcome of a whole run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// Still running, or interrupted before it could finish
+    Running,
+    Passed,
+    Failed,
+}
+
+impl std::fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RunStatus::Running => "running",
+            RunStatus::Passed => "passed",
+            RunStatus::Failed => "failed",
+        })
+    }
+}
+
+/// Contents of `run.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: String,
+    pub status: RunStatus,
+    /// RFC 3339 timestamps
+    pub started_at: String,
+    #[serde(default)]
+    pub finished_at: Option<String>,
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
+    /// Configuration file the run used
+    pub config_file: String,
+    #[serde(default)]
+    pub git_branch: Option<String>,
+    #[serde(default)]
+    pub git_sha: Option<String>,
+    /// Error that ended the run, with secrets masked
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Run whose results this run reused, for `run --rerun-failed`
+    #[serde(default)]
+    pub rerun_of: Option<String>,
+    #[serde(default)]
+    pub steps: Vec<StepRecord>,
+}
+
+/// A step of a run, in configuration order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub stage: String,
+    pub name: String,
+    pub status: StepStatus,
+    #[serde(default)]
+    pub exit_code: Option<i64>,
+    #[serde(default)]
+    pub duration_secs: Option<f64>,
+    /// Log file relative to the run directory; steps that did not run have none
+    #[serde(default)]
+    pub log: Option<String>,
+    /// Outputs the step wrote to `FORGE_OUTPUT`, with secrets masked
+    #[serde(default)]
+    pub outputs: HashMap<String, String>,
+}
+
+impl RunRecord {
+    /// Write `run.json`, replacing it only once complete.
+    pub fn save(&self, run_dir: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        let partial = run_dir.join("run.json.partial");
+        std::fs::write(&partial, json + "\n")?;
+        std::fs::rename(partial, run_dir.join("run.json"))
+    }
+
+    /// Read the `run.json` of a run directory.
+    pub fn load(run_dir: &Path) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(run_dir.join("run.json"))?;
+        serde_json::from_str(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// A recorded run and the directory holding it.
+pub struct Run {
+    pub dir: PathBuf,
+    pub record: RunRecord,
+}
+
+/// Recorded runs of a project, newest first. Directories without a readable `run.json`
+/// are skipped.
+pub fn list(project_dir: &Path) -> std::io::Result<Vec<Run>> {
+    let entries = match std::fs::read_dir(runs_dir(project_dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut runs = Vec::new();
+    for entry in entries {
+        let dir = entry?.path();
+        if let Ok(record) = RunRecord::load(&dir) {
+            runs.push(Run { dir, record });
+        }
+    }
+    // Run ids start with their start time
+    runs.sort_by(|a, b| b.record.id.cmp(&a.record.id));
+    Ok(runs)
+}
+
+/// The run whose id is `id` or starts with it, or the latest run if `id` is `None`.
+pub fn find(project_dir: &Path, id: Option<&str>) -> std::io::Result<Run> {
+    let not_found = |message: String| std::io::Error::new(std::io::ErrorKind::NotFound, message);
+    let mut runs = list(project_dir)?;
+    if runs.is_empty() {
+        return Err(not_found(format!(
+            "No recorded runs in {}\n\
+             Hint: 'forge-cli run' records every run of the project",
+            runs_dir(project_dir).display()
+        )));
+    }
+    let Some(id) = id else {
+        return Ok(runs.remove(0));
+    };
+    if let Some(index) = runs.iter().position(|run| run.record.id == id) {
+        return Ok(runs.remove(index));
+    }
+    let mut matching: Vec<Run> = runs
+        .into_iter()
+        .filter(|run| run.record.id.starts_with(id))
+        .collect();
+    match matching.len() {
+        0 => Err(not_found(format!(
+            "No run matches '{id}'\n\
+             Hint: Run 'forge-cli history' to see the recorded runs"
+        ))),
+        1 => Ok(matching.remove(0)),
+        _ => {
+            let ids: Vec<&str> = matching.iter().map(|run| run.record.id.as_str()).collect();
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Run id '{id}' is ambiguous, it matches {}\n\
+                     Hint: Give more characters of the run id",
+                    ids.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// Remove ANSI escape sequences, for printing logs where colours are turned off.
+pub fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+        } else if chars.next() == Some('[') {
+            // Parameters and intermediate bytes up to the final byte of the sequence
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Appends a step's output to its log file.
+///
+/// Failing to write the log only prints a warning once; the step itself carries on.
+pub struct StepLog {
+    file: Option<std::fs::File>,
+}
+
+impl StepLog {
+    /// Open `path` for appending, so that the attempts of a retried step share one log.
+    pub fn open(path: &Path) -> Self {
+        let file = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+            });
+        match file {
+            Ok(file) => StepLog { file: Some(file) },
+            Err(e) => {
+                warn(&format!("Failed to open log '{}': {e}", path.display()));
+                StepLog { file: None }
+            }
+        }
+    }
+
+    /// Append a line of stderr output, shown in red like on the terminal.
+    pub fn stderr(&mut self, line: &str) {
+        self.line(&format!("\x1b[31m{line}\x1b[0m"));
+    }
+
+    /// Append one line.
+    pub fn line(&mut self, line: &str) {
+        if let Some(file) = &mut self.file
+            && let Err(e) = writeln!(file, "{line}")
+        {
+            warn(&format!("Failed to write log: {e}"));
+            self.file = None;
+        }
+    }
+}
+
+fn warn(message: &str) {
+    use colored::Colorize;
+    eprintln!("{}", format!("Warning: {message}").yellow());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip_through_run_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = RunRecord {
+            id: "20240501-142233-3f9a1c".to_string(),
+            status: RunStatus::Failed,
+            started_at: "2024-05-01T14:22:33+02:00".to_string(),
+            finished_at: Some("2024-05-01T14:23:00+02:00".to_string()),
+            duration_secs: Some(27.0),
+            config_file: "forge.yaml".to_string(),
+            git_branch: Some("main".to_string()),
+            git_sha: None,
+            error: Some("Step 'test' failed".to_string()),
+            rerun_of: None,
+            steps: vec![StepRecord {
+                stage: "test".to_string(),
+                name: "Unit Tests".to_string(),
+                status: StepStatus::FailedAllowed,
+                exit_code: Some(1),
+                duration_secs: Some(12.5),
+                log: Some("logs/test/01-Unit_Tests.log".to_string()),
+                outputs: HashMap::from([("coverage".to_string(), "87%".to_string())]),
+            }],
+        };
+        record.save(dir.path()).unwrap();
+
+        let json = std::fs::read_to_string(dir.path().join("run.json")).unwrap();
+        assert!(json.contains("\"status\": \"failed_allowed\""));
+        let loaded = RunRecord::load(dir.path()).unwrap();
+        assert_eq!(loaded.status, RunStatus::Failed);
+        assert_eq!(loaded.steps[0].status, StepStatus::FailedAllowed);
+        assert_eq!(loaded.steps[0].exit_code, Some(1));
+        assert_eq!(loaded.steps[0].outputs["coverage"], "87%");
+    }
+
+    #[test]
+    fn logs_are_appended_per_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir
+            .path()
+            .join(log_file("build (node=18)", 0, "Unit Tests"));
+        assert!(path.ends_with("logs/build__node_18_/01-Unit_Tests.log"));
+        assert_ne!(log_file("build", 1, "a/b"), log_file("build", 2, "a:b"));
+
+        StepLog::open(&path).line("attempt 1");
+        StepLog::open(&path).line("attempt 2");
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log, "attempt 1\nattempt 2\n");
+
+        let mut log = StepLog::open(&path);
+        log.stderr("\x1b[1mnpm ERR!\x1b[22m missing script");
+        let log = std::fs::read_to_string(&path).unwrap();
+        let last = log.lines().last().unwrap();
+        assert_eq!(
+            last,
+            "\x1b[31m\x1b[1mnpm ERR!\x1b[22m missing script\x1b[0m"
+        );
+        assert_eq!(strip_ansi(last), "npm ERR! missing script");
+    }
+
+    #[test]
+    fn finds_runs_by_id_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        for id in [
+            "20240501-090000-aaaaaa",
+            "20240502-090000-bbbbbb",
+            "20240502-100000-cccccc",
+        ] {
+            let run_dir = run_dir(dir.path(), id);
+            std::fs::create_dir_all(&run_dir).unwrap();
+            let record = RunRecord {
+                id: id.to_string(),
+                status: RunStatus::Passed,
+                started_at: String::new(),
+                finished_at: None,
+                duration_secs: None,
+                config_file: "forge.yaml".to_string(),
+                git_branch: None,
+                git_sha: None,
+                error: None,
+                rerun_of: None,
+                steps: vec![],
+            };
+            record.save(&run_dir).unwrap();
+        }
+        std::fs::create_dir_all(runs_dir(dir.path()).join("not-a-run")).unwrap();
+
+        let ids: Vec<String> = list(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|r| r.record.id)
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "20240502-100000-cccccc",
+                "20240502-090000-bbbbbb",
+                "20240501-090000-aaaaaa"
+            ]
+        );
+        let find = |id| find(dir.path(), id).map(|run| run.record.id);
+        assert_eq!(find(None).unwrap(), "20240502-100000-cccccc");
+        assert_eq!(find(Some("20240501")).unwrap(), "20240501-090000-aaaaaa");
+        assert_eq!(
+            find(Some("20240502-090000-bbbbbb")).unwrap(),
+            "20240502-090000-bbbbbb"
+        );
+        assert!(find(Some("20240502")).is_err());
+        assert!(find(Some("2023")).is_err());
+        assert!(super::find(tempfile::tempdir().unwrap().path(), None).is_err());
+    }
+}
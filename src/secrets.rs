@@ -0,0 +1,384 @@
+//! Resolution and masking of the secrets declared in the configuration.
+//!
+//! Secret values are read from the host environment once per run and handed to every
+//! step container as environment variables. Anything FORGE prints afterwards goes
+//! through [`Secrets::mask`] so the values never reach the terminal.
+
+use crate::Secret;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use std::fmt;
+
+/// Replacement for secret values in output.
+pub const MASK: &str = "***";
+
+/// Lines longer than this are flushed even without a newline.
+const MAX_LINE_BYTES: usize = 16 * 1024;
+
+/// A secret whose value has been read from the host.
+#[derive(Debug, Clone)]
+struct ResolvedSecret {
+    name: String,
+    value: String,
+}
+
+/// The secrets available to a pipeline run.
+#[derive(Debug, Default)]
+pub struct Secrets {
+    resolved: Vec<ResolvedSecret>,
+    /// Strings to mask, longest first so encodings win over their substrings
+    patterns: Vec<String>,
+}
+
+impl Secrets {
+    /// Read every declared secret from the host environment.
+    pub fn resolve(secrets: &[Secret]) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Self::resolve_with(secrets, |var| std::env::var(var).ok())
+    }
+
+    /// Resolve secrets using `lookup` to read host variables.
+    ///
+    /// Missing variables are an error unless the secret is marked `optional`, in which
+    /// case the secret is simply not injected.
+    fn resolve_with(
+        secrets: &[Secret],
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut resolved = Vec::with_capacity(secrets.len());
+        let mut missing = Vec::new();
+
+        for secret in secrets {
+            match lookup(&secret.env_var) {
+                Some(value) => resolved.push(ResolvedSecret {
+                    name: secret.name.clone(),
+                    value,
+                }),
+                None if secret.optional => {}
+                None => missing.push(format!(
+                    "• {} (from host variable {})",
+                    secret.name, secret.env_var
+                )),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "Missing secrets:\n{}\n\
+                     Hint: Export the host environment variables before running, \
+                     or mark the secret with 'optional: true'",
+                    missing.join("\n")
+                ),
+            )));
+        }
+
+        let patterns = mask_patterns(resolved.iter().map(|s| s.value.as_str()));
+        Ok(Secrets { resolved, patterns })
+    }
+
+    /// Names of the secrets that will be injected.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.resolved.iter().map(|s| s.name.as_str())
+    }
+
+    /// `(name, value)` pairs to inject into step containers.
+    pub fn env(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.resolved
+            .iter()
+            .map(|s| (s.name.as_str(), s.value.as_str()))
+    }
+
+    /// Replace every secret value, and its base64 encodings, with `***`.
+    pub fn mask(&self, text: &str) -> String {
+        let mut masked = text.to_string();
+        for pattern in &self.patterns {
+            if masked.contains(pattern.as_str()) {
+                masked = masked.replace(pattern.as_str(), MASK);
+            }
+        }
+        masked
+    }
+
+    /// Mask an error message, keeping the original error reachable as its source.
+    pub fn mask_error(
+        &self,
+        error: Box<dyn std::error::Error + Send + Sync>,
+    ) -> Box<dyn std::error::Error + Send + Sync> {
+        let message = error.to_string();
+        let masked = self.mask(&message);
+        if masked == message {
+            error
+        } else {
+            Box::new(MaskedError {
+                message: masked,
+                inner: error,
+            })
+        }
+    }
+
+    /// Longest secret pattern, i.e. how much text a value can span.
+    fn longest_pattern(&self) -> usize {
+        self.patterns.first().map_or(0, String::len)
+    }
+
+    /// Move `cut` back so that it does not split a secret value appearing in `text`.
+    fn cut_before_secrets(&self, text: &[u8], mut cut: usize) -> usize {
+        loop {
+            let crossing = self
+                .patterns
+                .iter()
+                .filter_map(|pattern| {
+                    let pattern = pattern.as_bytes();
+                    let first = (cut + 1).saturating_sub(pattern.len());
+                    (first..cut).find(|&start| text[start..].starts_with(pattern))
+                })
+                .min();
+            match crossing {
+                Some(start) => cut = start,
+                None => return cut,
+            }
+        }
+    }
+}
+
+/// Collect the strings to mask for the given secret values.
+fn mask_patterns<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut patterns = Vec::new();
+    for value in values {
+        let mut candidates = vec![value];
+        // Multi-line values (keys, certificates) are printed line by line
+        candidates.extend(value.lines().map(str::trim).filter(|l| l.len() > 2));
+
+        for candidate in candidates {
+            if candidate.trim().is_empty() {
+                continue;
+            }
+            patterns.push(candidate.to_string());
+            for engine in [STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD] {
+                patterns.push(engine.encode(candidate));
+            }
+        }
+    }
+    patterns.sort_by_key(|p| std::cmp::Reverse(p.len()));
+    patterns.dedup();
+    patterns
+}
+
+/// An error whose message had secret values masked.
+#[derive(Debug)]
+struct MaskedError {
+    message: String,
+    inner: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl fmt::Display for MaskedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MaskedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.inner.as_ref())
+    }
+}
+
+/// Splits a stream of log chunks into masked lines.
+///
+/// Docker delivers output in arbitrary chunks, so a secret can arrive split across two of
+/// them. Text is held back until a full line is available; overly long lines are flushed
+/// early but keep enough of their tail to still catch a value straddling the cut.
+pub struct MaskedLines<'a> {
+    secrets: &'a Secrets,
+    buffer: Vec<u8>,
+}
+
+impl<'a> MaskedLines<'a> {
+    pub fn new(secrets: &'a Secrets) -> Self {
+        MaskedLines {
+            secrets,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Add a chunk of output and return the lines it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            lines.push(self.emit(&line[..end]));
+        }
+
+        if self.buffer.len() > MAX_LINE_BYTES {
+            let cut = self.buffer.len() - self.secrets.longest_pattern().min(MAX_LINE_BYTES);
+            // Never split a secret that is already complete, nor a UTF-8 character
+            let mut cut = self.secrets.cut_before_secrets(&self.buffer, cut);
+            while cut > 0
+                && cut < self.buffer.len()
+                && (self.buffer[cut] & 0b1100_0000) == 0b1000_0000
+            {
+                cut -= 1;
+            }
+            if cut > 0 {
+                let head: Vec<u8> = self.buffer.drain(..cut).collect();
+                lines.push(self.emit(&head));
+            }
+        }
+
+        lines
+    }
+
+    /// Return whatever is left once the stream has ended.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let rest = std::mem::take(&mut self.buffer);
+        Some(self.emit(&rest))
+    }
+
+    fn emit(&self, bytes: &[u8]) -> String {
+        let text = String::from_utf8_lossy(bytes);
+        self.secrets.mask(text.trim_end_matches('\r'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(name: &str, env_var: &str, optional: bool) -> Secret {
+        Secret {
+            name: name.to_string(),
+            env_var: env_var.to_string(),
+            optional,
+        }
+    }
+
+    fn lookup(var: &str) -> Option<String> {
+        (var == "HOST_TOKEN").then(|| "s3cr3t".to_string())
+    }
+
+    #[test]
+    fn resolves_values_under_the_secret_name() {
+        let secrets =
+            Secrets::resolve_with(&[secret("API_TOKEN", "HOST_TOKEN", false)], lookup).unwrap();
+        assert_eq!(
+            secrets.env().collect::<Vec<_>>(),
+            vec![("API_TOKEN", "s3cr3t")]
+        );
+    }
+
+    #[test]
+    fn missing_required_secret_is_an_error() {
+        let err =
+            Secrets::resolve_with(&[secret("API_TOKEN", "UNSET_VAR", false)], lookup).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("API_TOKEN (from host variable UNSET_VAR)")
+        );
+    }
+
+    fn resolved() -> Secrets {
+        Secrets::resolve_with(&[secret("API_TOKEN", "HOST_TOKEN", false)], lookup).unwrap()
+    }
+
+    #[test]
+    fn masks_values_and_base64_encodings() {
+        let secrets = resolved();
+        assert_eq!(secrets.mask("token=s3cr3t;"), "token=***;");
+        assert_eq!(secrets.mask("auth czNjcjN0"), "auth ***");
+        assert_eq!(secrets.mask("nothing here"), "nothing here");
+    }
+
+    #[test]
+    fn masks_values_split_across_chunks() {
+        let secrets = resolved();
+        let mut lines = MaskedLines::new(&secrets);
+        assert!(lines.push(b"key: s3c").is_empty());
+        assert_eq!(lines.push(b"r3t\nlast "), vec!["key: ***"]);
+        assert!(lines.push(b"line").is_empty());
+        assert_eq!(lines.finish().as_deref(), Some("last line"));
+    }
+
+    #[test]
+    fn flushes_long_lines_without_secrets() {
+        let secrets = Secrets::default();
+        let mut lines = MaskedLines::new(&secrets);
+        let flushed = lines.push(&[b'a'; 20 * 1024]);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].len(), 20 * 1024);
+        assert_eq!(lines.finish(), None);
+    }
+
+    #[test]
+    fn long_lines_are_not_cut_inside_a_secret() {
+        // The long secret makes the flush keep 136 bytes (its base64 encoding), which puts
+        // the cut in the middle of the short one
+        let secrets = Secrets::resolve_with(
+            &[
+                secret("API_TOKEN", "HOST_TOKEN", false),
+                secret("LONG", "LONG_TOKEN", false),
+            ],
+            |var| match var {
+                "LONG_TOKEN" => Some("L".repeat(100)),
+                var => lookup(var),
+            },
+        )
+        .unwrap();
+        let mut lines = MaskedLines::new(&secrets);
+        let mut chunk = vec![b'a'; 17000];
+        chunk.extend_from_slice(b"s3cr3t");
+        chunk.extend_from_slice(&[b'a'; 133]);
+
+        let flushed = lines.push(&chunk);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0], "a".repeat(17000));
+        let rest = lines.finish().unwrap();
+        assert_eq!(rest, format!("***{}", "a".repeat(133)));
+    }
+
+    #[test]
+    fn masked_error_keeps_its_source() {
+        let secrets = resolved();
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(std::io::Error::other("command failed: curl -H s3cr3t"));
+        let masked = secrets.mask_error(error);
+        assert_eq!(masked.to_string(), "command failed: curl -H ***");
+        assert!(masked.source().is_some());
+    }
+
+    #[test]
+    fn missing_optional_secret_is_skipped() {
+        let secrets =
+            Secrets::resolve_with(&[secret("API_TOKEN", "UNSET_VAR", true)], lookup).unwrap();
+        assert_eq!(secrets.names().count(), 0);
+    }
+
+    #[test]
+    fn secrets_are_read_from_the_config() {
+        let config: crate::ForgeConfig = serde_yaml::from_str(
+            r#"
+secrets:
+  - name: API_TOKEN
+    env_var: HOST_TOKEN
+  - name: DEPLOY_KEY
+    env_var: UNSET_VAR
+    optional: true
+"#,
+        )
+        .unwrap();
+        let secrets = Secrets::resolve_with(&config.secrets, lookup).unwrap();
+        assert_eq!(
+            secrets.env().collect::<Vec<_>>(),
+            vec![("API_TOKEN", "s3cr3t")]
+        );
+
+        let required: crate::ForgeConfig =
+            serde_yaml::from_str("secrets: [{name: DEPLOY_KEY, env_var: UNSET_VAR}]").unwrap();
+        assert!(Secrets::resolve_with(&required.secrets, lookup).is_err());
+    }
+}
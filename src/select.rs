@@ -0,0 +1,477 @@
+//! Running part of the pipeline, for the selection options of `forge-cli run`.
+//!
+//! Stage options follow the stage graph:
+//!
+//! - `--stage NAME`: the stage and the stages it depends on, directly or indirectly
+//! - `--from NAME`: the stage and the stages depending on it; its own dependencies are left out
+//! - `--until NAME`: every stage except those depending on the stage
+//!
+//! `--only` and `--skip` then pick steps by name or glob pattern, e.g. `test*`. The name of
+//! a stage or step with a matrix selects all of its copies. Dependencies on stages and steps
+//! that are left out are dropped, so the selected ones do not wait for them. Artifacts and
+//! outputs are different: a selected step needing the artifact of a step that is left out,
+//! or referring to its outputs, is an error.
+
+use crate::Stage;
+use crate::graph::DependencyGraph;
+use std::collections::{HashMap, HashSet};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// The parts of the pipeline to run; everything is selected by default.
+#[derive(Default)]
+pub struct Selection<'a> {
+    pub stage: Option<&'a str>,
+    pub from: Option<&'a str>,
+    pub until: Option<&'a str>,
+    pub only: &'a [String],
+    pub skip: &'a [String],
+}
+
+impl Selection<'_> {
+    /// Remove the stages and steps that are not selected.
+    pub fn apply(&self, stages: &mut Vec<Stage>) -> Result<(), Error> {
+        let graph = DependencyGraph::new(
+            stages
+                .iter()
+                .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+        )?;
+        let mut dependents = vec![Vec::new(); graph.len()];
+        for node in 0..graph.len() {
+            for &dep in graph.dependencies(node) {
+                dependents[dep].push(node);
+            }
+        }
+
+        let mut selected = vec![true; stages.len()];
+        if let Some(name) = self.stage {
+            let upstream = reachable(&find_stages(stages, name)?, |n| graph.dependencies(n));
+            intersect(&mut selected, &upstream);
+        }
+        if let Some(name) = self.from {
+            let downstream = reachable(&find_stages(stages, name)?, |n| &dependents[n]);
+            intersect(&mut selected, &downstream);
+        }
+        if let Some(name) = self.until {
+            let found = find_stages(stages, name)?;
+            let mut downstream = reachable(&found, |n| &dependents[n]);
+            for node in found {
+                downstream[node] = false;
+            }
+            for (selected, downstream) in selected.iter_mut().zip(downstream) {
+                *selected &= !downstream;
+            }
+        }
+
+        let all_steps = || stages.iter().flat_map(|stage| &stage.steps);
+        for (option, patterns) in [("--only", self.only), ("--skip", self.skip)] {
+            for pattern in patterns {
+                if !all_steps().any(|step| step_matches(pattern, step)) {
+                    let names: Vec<&str> = all_steps().map(|s| s.display_name()).collect();
+                    return Err(not_found(format!(
+                        "No step matches '{pattern}' ({option})\n\
+                         Available steps: {}\n\
+                         Hint: Give a step name or a glob pattern such as 'test*'",
+                        list(&names)
+                    )));
+                }
+            }
+        }
+
+        let producers: HashMap<String, String> = all_steps()
+            .filter_map(|step| {
+                let name = crate::cache::sanitize_key(step.artifact_name()?);
+                Some((name, step.display_name().to_string()))
+            })
+            .collect();
+        let step_names: HashMap<String, HashSet<String>> = stages
+            .iter()
+            .map(|stage| {
+                let names = stage.steps.iter().map(|s| s.display_name().to_string());
+                (stage.name.clone(), names.collect())
+            })
+            .collect();
+
+        let mut selected = selected.into_iter();
+        stages.retain(|_| selected.next().unwrap_or(false));
+        for stage in stages.iter_mut() {
+            stage.steps.retain(|step| {
+                (self.only.is_empty() || self.only.iter().any(|p| step_matches(p, step)))
+                    && !self.skip.iter().any(|p| step_matches(p, step))
+            });
+            let names: HashSet<String> = stage.steps.iter().map(|s| s.name.clone()).collect();
+            for step in &mut stage.steps {
+                step.depends_on.retain(|dep| names.contains(dep));
+            }
+        }
+        stages.retain(|stage| !stage.steps.is_empty());
+        if stages.is_empty() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No steps left to run with the given --stage, --from, --until, --only and \
+                 --skip options\n\
+                 Hint: Run 'forge-cli validate' to see the stages and steps of the pipeline",
+            )));
+        }
+        let names: HashSet<String> = stages.iter().map(|s| s.name.clone()).collect();
+        for stage in stages.iter_mut() {
+            stage.depends_on.retain(|dep| names.contains(dep));
+        }
+        check_artifacts(stages, &producers)?;
+        check_outputs(stages, &step_names)
+    }
+}
+
+/// Check that the artifacts needed by the selected steps are produced by selected steps.
+/// `producers` maps every artifact of the whole pipeline to the step producing it.
+fn check_artifacts(stages: &[Stage], producers: &HashMap<String, String>) -> Result<(), Error> {
+    let steps = || stages.iter().flat_map(|stage| &stage.steps);
+    let produced: HashSet<String> = steps()
+        .filter_map(|step| step.artifact_name())
+        .map(crate::cache::sanitize_key)
+        .collect();
+    for step in steps() {
+        for name in &step.needs_artifacts {
+            let key = crate::cache::sanitize_key(name);
+            if produced.contains(&key) {
+                continue;
+            }
+            let producer = producers.get(&key).map_or("", String::as_str);
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Step '{}' needs artifact '{name}' of step '{producer}', which is not selected\n\
+                     Hint: Select step '{producer}' as well, or leave out the steps needing its \
+                     artifact",
+                    step.display_name()
+                ),
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the outputs referred to by the selected steps come from selected steps.
+/// `step_names` maps every stage of the whole pipeline to the names of its steps.
+fn check_outputs(
+    stages: &[Stage],
+    step_names: &HashMap<String, HashSet<String>>,
+) -> Result<(), Error> {
+    let has_step = |stage: &Stage, name: &str| stage.steps.iter().any(|s| s.display_name() == name);
+    for stage in stages {
+        for step in &stage.steps {
+            let fields = [&step.image, &step.working_dir, &step.command];
+            let references = fields
+                .into_iter()
+                .chain(step.env.values())
+                .flat_map(|text| crate::outputs::references(text));
+            for (name, key) in references {
+                // Like `lookup_output`: a step of the same stage, else one of another stage
+                let same_stage = step_names
+                    .get(&stage.name)
+                    .is_some_and(|names| names.contains(&name));
+                let selected = if same_stage {
+                    has_step(stage, &name)
+                } else {
+                    stages.iter().any(|other| has_step(other, &name))
+                };
+                let known = step_names.values().any(|names| names.contains(&name));
+                if selected || !known {
+                    continue;
+                }
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Step '{}' uses output '{key}' of step '{name}', which is not selected\n\
+                         Hint: Select step '{name}' as well, or leave out the steps using its \
+                         outputs",
+                        step.display_name()
+                    ),
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Positions of the stages called `name`: the stage itself or the copies of a stage with
+/// a matrix.
+fn find_stages(stages: &[Stage], name: &str) -> Result<Vec<usize>, Error> {
+    let found: Vec<usize> = stages
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.name == name || s.job.as_ref().is_some_and(|j| j.group == name))
+        .map(|(index, _)| index)
+        .collect();
+    if found.is_empty() {
+        let names: Vec<&str> = stages.iter().map(|s| s.name.as_str()).collect();
+        return Err(not_found(format!(
+            "Stage '{name}' not found in configuration\n\
+             Available stages: {}\n\
+             Hint: Check your forge.yaml file for correct stage names",
+            list(&names)
+        )));
+    }
+    Ok(found)
+}
+
+/// Whether a step's name, or the name of the step its matrix copies were made from,
+/// matches `pattern`.
+fn step_matches(pattern: &str, step: &crate::Step) -> bool {
+    let matches = |name: &str| name == pattern || crate::glob::matches(pattern, name);
+    matches(step.display_name()) || step.job.as_ref().is_some_and(|j| matches(&j.group))
+}
+
+/// The nodes reachable from `start` by following `next`, including `start` itself.
+fn reachable<'a>(start: &[usize], next: impl Fn(usize) -> &'a [usize]) -> Vec<bool> {
+    let mut reached = Vec::new();
+    let mut pending = start.to_vec();
+    while let Some(node) = pending.pop() {
+        if reached.len() <= node {
+            reached.resize(node + 1, false);
+        }
+        if !reached[node] {
+            reached[node] = true;
+            pending.extend_from_slice(next(node));
+        }
+    }
+    reached
+}
+
+fn intersect(selected: &mut [bool], other: &[bool]) {
+    for (index, selected) in selected.iter_mut().enumerate() {
+        *selected &= other.get(index).copied().unwrap_or(false);
+    }
+}
+
+fn list(names: &[&str]) -> String {
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+fn not_found(message: String) -> Error {
+    Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stages() -> Vec<Stage> {
+        let mut config: crate::ForgeConfig = serde_yaml::from_str(
+            r#"
+stages:
+  - name: setup
+    steps:
+      - name: install
+        command: npm ci
+  - name: lint
+    depends_on: [setup]
+    steps:
+      - name: eslint
+        command: npm run lint
+  - name: test
+    depends_on: [setup]
+    parallel: true
+    steps:
+      - name: unit
+        command: npm test
+        matrix:
+          node: ["18", "20"]
+      - name: e2e
+        command: npm run e2e
+      - name: report
+        command: npm run report
+        depends_on: [unit, e2e]
+  - name: build
+    depends_on: [test]
+    steps:
+      - name: bundle
+        command: npm run build
+        artifacts:
+          paths: [dist]
+  - name: deploy
+    depends_on: [build, lint]
+    steps:
+      - name: upload
+        command: ./deploy.sh
+        needs_artifacts: [bundle]
+"#,
+        )
+        .unwrap();
+        crate::matrix::expand(&mut config).unwrap();
+        config.stages
+    }
+
+    fn select(selection: Selection) -> Vec<String> {
+        let mut stages = stages();
+        selection.apply(&mut stages).unwrap();
+        stages
+            .iter()
+            .map(|stage| {
+                let steps: Vec<&str> = stage.steps.iter().map(|s| s.display_name()).collect();
+                format!(
+                    "{}[{}]<-{}",
+                    stage.name,
+                    steps.join(","),
+                    stage.depends_on.join(",")
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stage_options_follow_the_graph() {
+        let selection = Selection {
+            stage: Some("build"),
+            ..Default::default()
+        };
+        assert_eq!(
+            select(selection),
+            [
+                "setup[install]<-",
+                "test[unit (node=18),unit (node=20),e2e,report]<-setup",
+                "build[bundle]<-test"
+            ]
+        );
+
+        let selection = Selection {
+            from: Some("test"),
+            ..Default::default()
+        };
+        assert_eq!(
+            select(selection),
+            [
+                "test[unit (node=18),unit (node=20),e2e,report]<-",
+                "build[bundle]<-test",
+                "deploy[upload]<-build"
+            ]
+        );
+
+        let selection = Selection {
+            from: Some("test"),
+            until: Some("build"),
+            ..Default::default()
+        };
+        assert_eq!(
+            select(selection),
+            [
+                "test[unit (node=18),unit (node=20),e2e,report]<-",
+                "build[bundle]<-test"
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_are_selected_by_name_or_pattern() {
+        let only = ["unit".to_string(), "rep*".to_string()];
+        let selection = Selection {
+            only: &only,
+            ..Default::default()
+        };
+        assert_eq!(
+            select(selection),
+            ["test[unit (node=18),unit (node=20),report]<-"]
+        );
+
+        let skip = ["e2e".to_string(), "unit (node=18)".to_string()];
+        let selection = Selection {
+            stage: Some("test"),
+            skip: &skip,
+            ..Default::default()
+        };
+        assert_eq!(
+            select(selection),
+            ["setup[install]<-", "test[unit (node=20),report]<-setup"]
+        );
+        let mut stages = stages();
+        Selection {
+            stage: Some("test"),
+            skip: &skip,
+            ..Default::default()
+        }
+        .apply(&mut stages)
+        .unwrap();
+        assert_eq!(stages[1].steps[1].depends_on, ["unit (node=20)"]);
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        let mut stages = stages();
+        let selection = Selection {
+            until: Some("release"),
+            ..Default::default()
+        };
+        let error = selection.apply(&mut stages).unwrap_err().to_string();
+        assert!(error.contains("Stage 'release' not found"));
+
+        let skip = ["lint".to_string()];
+        let selection = Selection {
+            skip: &skip,
+            ..Default::default()
+        };
+        let error = selection.apply(&mut stages).unwrap_err().to_string();
+        assert!(error.contains("No step matches 'lint' (--skip)"));
+        assert!(error.contains("eslint"));
+
+        let only = ["eslint".to_string()];
+        let selection = Selection {
+            from: Some("build"),
+            only: &only,
+            ..Default::default()
+        };
+        assert!(selection.apply(&mut stages).is_err());
+    }
+
+    #[test]
+    fn needed_artifacts_must_be_selected() {
+        let mut stages = stages();
+        let selection = Selection {
+            from: Some("deploy"),
+            ..Default::default()
+        };
+        let error = selection.apply(&mut stages).unwrap_err().to_string();
+        assert!(error.starts_with(
+            "Step 'upload' needs artifact 'bundle' of step 'bundle', which is not selected"
+        ));
+
+        let only = ["bundle".to_string(), "upload".to_string()];
+        let selection = Selection {
+            only: &only,
+            ..Default::default()
+        };
+        assert_eq!(
+            select(selection),
+            ["build[bundle]<-", "deploy[upload]<-build"]
+        );
+    }
+
+    #[test]
+    fn referenced_outputs_must_be_selected() {
+        let stages = || {
+            let mut stages = stages();
+            let upload = &mut stages[4].steps[0];
+            upload.command = "./deploy.sh ${{ steps.bundle.outputs.version }}".to_string();
+            upload.needs_artifacts.clear();
+            stages
+        };
+        let selection = Selection {
+            from: Some("deploy"),
+            ..Default::default()
+        };
+        let error = selection.apply(&mut stages()).unwrap_err().to_string();
+        assert!(error.starts_with(
+            "Step 'upload' uses output 'version' of step 'bundle', which is not selected"
+        ));
+
+        let only = ["bundle".to_string(), "upload".to_string()];
+        let selection = Selection {
+            only: &only,
+            ..Default::default()
+        };
+        assert!(selection.apply(&mut stages()).is_ok());
+    }
+}